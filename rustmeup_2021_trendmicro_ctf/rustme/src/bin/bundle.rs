@@ -0,0 +1,124 @@
+//! Bundle a built host and a guest into one self-contained challenge
+//! executable
+//!
+//! bundle --host rustme --guest har_riscv --out rustmeup
+//!        [--memory-size SIZE] [--entry ADDR] [--keys FILE --times FILE]
+//!
+//! The key file holds whitespace separated rc4 keys and the time file as
+//! many whitespace separated numbers.
+
+#[path = "../bundle.rs"]
+mod bundle;
+
+use bundle::Metadata;
+use std::process::exit;
+
+/// `e_machine` of RISC-V
+const EM_RISCV: u16 = 243;
+
+/// Parse a decimal or `0x` prefixed hex number
+fn parse_num(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None      => s.parse().ok(),
+    }
+}
+
+/// Read `path` or exit
+fn read(path: &str) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|err| {
+        eprintln!("Failed to read {}: {}", path, err);
+        exit(1);
+    })
+}
+
+/// Read the whitespace separated words of `path`
+fn read_words(path: &str) -> Vec<String> {
+    String::from_utf8_lossy(&read(path))
+        .split_whitespace().map(String::from).collect()
+}
+
+fn usage() -> ! {
+    eprintln!("usage: bundle --host HOST --guest GUEST --out OUT \
+               [--memory-size SIZE] [--entry ADDR] [--keys FILE --times FILE]");
+    exit(1);
+}
+
+fn main() {
+    let mut host = None;
+    let mut guest = None;
+    let mut out = None;
+    let mut metadata = Metadata::default();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--host"  => host = Some(value),
+            "--guest" => guest = Some(value),
+            "--out"   => out = Some(value),
+            "--memory-size" => {
+                metadata.memory_size = Some(parse_num(&value)
+                    .unwrap_or_else(|| usage()));
+            }
+            "--entry" => {
+                metadata.entry = Some(parse_num(&value)
+                    .unwrap_or_else(|| usage()));
+            }
+            "--keys"  => metadata.keys = read_words(&value),
+            "--times" => {
+                metadata.times = read_words(&value).iter().map(|x| {
+                    parse_num(x).unwrap_or_else(|| {
+                        eprintln!("Bad time {:?} in {}", x, value);
+                        exit(1);
+                    })
+                }).collect();
+            }
+            _ => usage(),
+        }
+    }
+    let (host, guest, out) = match (host, guest, out) {
+        (Some(host), Some(guest), Some(out)) => (host, guest, out),
+        _ => usage(),
+    };
+
+    if let Err(err) = metadata.validate() {
+        eprintln!("Bad metadata: {}", err);
+        exit(1);
+    }
+
+    let host_image = read(&host);
+    if bundle::parse(&host_image).is_some() {
+        eprintln!("{} is already a bundle", host);
+        exit(1);
+    }
+
+    let guest_image = read(&guest);
+    if !guest_image.starts_with(b"\x7fELF") || guest_image.get(18..20) !=
+            Some(&EM_RISCV.to_le_bytes()[..]) {
+        eprintln!("{} is not a RISC-V ELF", guest);
+        exit(1);
+    }
+
+    // Read the bundle back the way the host will before writing it out
+    let file = bundle::build(&host_image, &guest_image, &metadata);
+    let check = bundle::parse(&file).expect("Bundle has no trailer")
+        .expect("Bundle failed its integrity check");
+    assert!(check.guest == &guest_image[..] && check.metadata == metadata,
+            "Bundle does not round trip");
+    let guest_offset = check.guest_offset;
+
+    if let Err(err) = std::fs::write(&out, &file) {
+        eprintln!("Failed to write {}: {}", out, err);
+        exit(1);
+    }
+
+    // Keep the host's permissions, so the bundle stays executable
+    if let Ok(meta) = std::fs::metadata(&host) {
+        let _ = std::fs::set_permissions(&out, meta.permissions());
+    }
+
+    println!("Bundled {} ({} bytes) at offset {:#x} into {}, checksum {:#018x}",
+             guest, guest_image.len(), guest_offset, out,
+             bundle::checksum(&file[..file.len() - 16]));
+}
@@ -0,0 +1,324 @@
+//! Self-contained challenge bundles built by the `bundle` tool
+//!
+//! A bundle is the host executable followed by the guest, the challenge
+//! metadata and a trailer:
+//!
+//! | field            | size |
+//! |------------------|------|
+//! | guest offset     | u64  |
+//! | guest size       | u64  |
+//! | metadata size    | u64  |
+//! | checksum         | u64  |
+//! | `BUNDLE_MAGIC`   | 8    |
+//!
+//! The metadata directly follows the guest. The checksum is the FNV-1a hash
+//! of every byte of the file before it, so the host, guest, metadata and
+//! layout are all covered. This module is shared with the bundler and must
+//! not depend on the rest of the crate.
+
+use std::convert::TryFrom;
+use std::io;
+
+/// Magic at the very end of a bundle
+pub const BUNDLE_MAGIC: &[u8; 8] = b"RUSTMEB1";
+
+/// Size of the bundle trailer
+pub const BUNDLE_TRAILER_SIZE: usize = 40;
+
+/// Metadata record tags
+const TAG_MEMORY_SIZE: u32 = 1;
+const TAG_ENTRY:       u32 = 2;
+const TAG_KEYS:        u32 = 3;
+const TAG_TIMES:       u32 = 4;
+
+/// Challenge settings shipped with the guest
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct Metadata {
+    /// Size of guest memory in bytes, the host default if not given
+    pub memory_size: Option<u64>,
+
+    /// Address execution starts at, overriding the guest's own entry
+    pub entry: Option<u64>,
+
+    /// rc4 keys for the guest's output, the built-in table if empty
+    pub keys: Vec<String>,
+
+    /// Time offsets returned by `gettimeofday`, one per key. The built-in
+    /// table if empty
+    pub times: Vec<u64>,
+}
+
+/// A bundle found in a file
+pub struct Bundle<'a> {
+    /// File offset of the guest
+    pub guest_offset: usize,
+
+    /// The guest executable
+    pub guest: &'a [u8],
+
+    /// The challenge settings
+    pub metadata: Metadata,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Little endian `u32` at `off` in `data`
+fn u32_at(data: &[u8], off: usize) -> Option<u32> {
+    data.get(off..off.checked_add(4)?)
+        .map(|x| u32::from_le_bytes(<[u8; 4]>::try_from(x).unwrap()))
+}
+
+/// Little endian `u64` at `off` in `data`
+fn u64_at(data: &[u8], off: usize) -> Option<u64> {
+    data.get(off..off.checked_add(8)?)
+        .map(|x| u64::from_le_bytes(<[u8; 8]>::try_from(x).unwrap()))
+}
+
+/// FNV-1a hash of `data`
+pub fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &x| {
+        (hash ^ x as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+impl Metadata {
+    /// Check the settings are usable
+    pub fn validate(&self) -> io::Result<()> {
+        if self.keys.len() != self.times.len() {
+            return Err(invalid("key and time tables differ in length"));
+        }
+        if self.keys.iter().any(|x| x.is_empty()) {
+            return Err(invalid("empty key"));
+        }
+        Ok(())
+    }
+
+    /// Serialize the settings as `(tag, length, payload)` records. Unset
+    /// settings are left out
+    pub fn encode(&self) -> Vec<u8> {
+        let mut records = Vec::new();
+        let mut record = |tag: u32, payload: &[u8]| {
+            records.extend_from_slice(&tag.to_le_bytes());
+            records.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            records.extend_from_slice(payload);
+        };
+
+        if let Some(size) = self.memory_size {
+            record(TAG_MEMORY_SIZE, &size.to_le_bytes());
+        }
+        if let Some(entry) = self.entry {
+            record(TAG_ENTRY, &entry.to_le_bytes());
+        }
+        if !self.keys.is_empty() {
+            let mut payload = Vec::new();
+            for key in &self.keys {
+                payload.extend_from_slice(&(key.len() as u32).to_le_bytes());
+                payload.extend_from_slice(key.as_bytes());
+            }
+            record(TAG_KEYS, &payload);
+        }
+        if !self.times.is_empty() {
+            let payload: Vec<u8> = self.times.iter()
+                .flat_map(|x| x.to_le_bytes()).collect();
+            record(TAG_TIMES, &payload);
+        }
+
+        records
+    }
+
+    /// Deserialize settings written by `encode`. Unknown records are skipped
+    pub fn decode(mut data: &[u8]) -> io::Result<Self> {
+        let truncated = || invalid("truncated metadata");
+        let mut metadata = Metadata::default();
+
+        while !data.is_empty() {
+            let tag = u32_at(data, 0).ok_or_else(truncated)?;
+            let len = u32_at(data, 4).ok_or_else(truncated)? as usize;
+            let payload = data.get(8..8 + len).ok_or_else(truncated)?;
+            data = &data[8 + len..];
+
+            match tag {
+                TAG_MEMORY_SIZE => {
+                    metadata.memory_size = Some(u64_at(payload, 0)
+                        .ok_or_else(truncated)?);
+                }
+                TAG_ENTRY => {
+                    metadata.entry = Some(u64_at(payload, 0)
+                        .ok_or_else(truncated)?);
+                }
+                TAG_KEYS => {
+                    let mut rest = payload;
+                    while !rest.is_empty() {
+                        let len = u32_at(rest, 0).ok_or_else(truncated)? as usize;
+                        let key = rest.get(4..4 + len).ok_or_else(truncated)?;
+                        metadata.keys.push(String::from_utf8(key.to_vec())
+                            .map_err(|_| invalid("key is not UTF-8"))?);
+                        rest = &rest[4 + len..];
+                    }
+                }
+                TAG_TIMES => {
+                    metadata.times = (0..payload.len()).step_by(8)
+                        .map(|off| u64_at(payload, off).ok_or_else(truncated))
+                        .collect::<io::Result<_>>()?;
+                }
+                _ => {}
+            }
+        }
+
+        metadata.validate()?;
+        Ok(metadata)
+    }
+}
+
+/// Append `guest`, `metadata` and the trailer to `host`
+pub fn build(host: &[u8], guest: &[u8], metadata: &Metadata) -> Vec<u8> {
+    let meta = metadata.encode();
+
+    let mut file = host.to_vec();
+    file.extend_from_slice(guest);
+    file.extend_from_slice(&meta);
+    file.extend_from_slice(&(host.len() as u64).to_le_bytes());
+    file.extend_from_slice(&(guest.len() as u64).to_le_bytes());
+    file.extend_from_slice(&(meta.len() as u64).to_le_bytes());
+
+    let sum = checksum(&file);
+    file.extend_from_slice(&sum.to_le_bytes());
+    file.extend_from_slice(BUNDLE_MAGIC);
+    file
+}
+
+/// Find the bundle in `file`. Returns `None` if `file` is not a bundle, and
+/// an error if it is one but fails the integrity check
+pub fn parse(file: &[u8]) -> Option<io::Result<Bundle<'_>>> {
+    let trailer = file.len().checked_sub(BUNDLE_TRAILER_SIZE)?;
+    if &file[file.len() - 8..] != BUNDLE_MAGIC {
+        return None;
+    }
+    Some(parse_at(file, trailer))
+}
+
+/// Check and split up the bundle with its trailer at `trailer`
+fn parse_at(file: &[u8], trailer: usize) -> io::Result<Bundle<'_>> {
+    if checksum(&file[..trailer + 24]) != u64_at(file, trailer + 24).unwrap() {
+        return Err(invalid("bundle checksum mismatch"));
+    }
+
+    let field = |idx: usize| u64_at(file, trailer + idx * 8)
+        .and_then(|x| usize::try_from(x).ok()).unwrap_or(usize::MAX);
+    let (guest_offset, guest_size, meta_size) = (field(0), field(1), field(2));
+
+    let meta_offset = guest_offset.checked_add(guest_size)
+        .filter(|&x| x.checked_add(meta_size) == Some(trailer))
+        .ok_or_else(|| invalid("bad bundle layout"))?;
+
+    Ok(Bundle {
+        guest_offset,
+        guest:    &file[guest_offset..meta_offset],
+        metadata: Metadata::decode(&file[meta_offset..trailer])?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata() -> Metadata {
+        Metadata {
+            memory_size: Some(32 * 1024 * 1024),
+            entry:       Some(0x1_0078),
+            keys:        vec!["first key".into(), "k2".into()],
+            times:       vec![1_633_000_000, 7],
+        }
+    }
+
+    /// Replace the trailer field `idx` of `file` with `value` and fix up the
+    /// checksum, so only the layout is wrong
+    fn reseal(file: &mut [u8], idx: usize, value: u64) {
+        let trailer = file.len() - BUNDLE_TRAILER_SIZE;
+        file[trailer + idx * 8..][..8].copy_from_slice(&value.to_le_bytes());
+        let sum = checksum(&file[..trailer + 24]);
+        file[trailer + 24..][..8].copy_from_slice(&sum.to_le_bytes());
+    }
+
+    fn error(file: &[u8]) -> String {
+        parse(file).expect("the file should be a bundle")
+            .err().expect("the bundle should be rejected").to_string()
+    }
+
+    #[test]
+    fn bundles_round_trip() {
+        let file = build(b"host executable", b"\x7fELF guest", &metadata());
+        let bundle = parse(&file).unwrap().unwrap();
+        assert_eq!(bundle.guest_offset, 15);
+        assert_eq!(bundle.guest, b"\x7fELF guest");
+        assert_eq!(bundle.metadata, metadata());
+
+        // Unset settings are left out entirely
+        let file = build(b"host", b"guest", &Metadata::default());
+        assert_eq!(file.len(), 4 + 5 + BUNDLE_TRAILER_SIZE);
+        assert_eq!(parse(&file).unwrap().unwrap().metadata, Metadata::default());
+
+        assert!(parse(b"host executable").is_none());
+        assert!(parse(BUNDLE_MAGIC).is_none());
+    }
+
+    #[test]
+    fn flipped_bytes_fail_the_checksum() {
+        let file = build(b"host executable", b"\x7fELF guest", &metadata());
+        for idx in [0, 15, file.len() - 20, file.len() - 9] {
+            let mut file = file.clone();
+            file[idx] ^= 0x40;
+            assert_eq!(error(&file), "bundle checksum mismatch", "byte {}", idx);
+        }
+    }
+
+    #[test]
+    fn bad_layouts_are_rejected() {
+        let file = build(b"host", b"guest", &metadata());
+        for &(idx, value) in &[(0, 5), (0, u64::MAX), (1, 4), (1, u64::MAX - 3),
+                               (2, 0)] {
+            let mut file = file.clone();
+            reseal(&mut file, idx, value);
+            assert_eq!(error(&file), "bad bundle layout", "field {} = {}", idx, value);
+        }
+    }
+
+    #[test]
+    fn truncated_metadata_is_rejected() {
+        let data = metadata().encode();
+        for len in [1, 7, 8, 15, data.len() - 1] {
+            let err = Metadata::decode(&data[..len]).unwrap_err();
+            assert_eq!(err.to_string(), "truncated metadata", "length {}", len);
+        }
+
+        // A key longer than its record
+        let mut record = TAG_KEYS.to_le_bytes().to_vec();
+        record.extend_from_slice(&6u32.to_le_bytes());
+        record.extend_from_slice(&9u32.to_le_bytes());
+        record.extend_from_slice(b"ke");
+        assert_eq!(Metadata::decode(&record).unwrap_err().to_string(),
+                   "truncated metadata");
+
+        // Unknown records are skipped
+        let mut data = 99u32.to_le_bytes().to_vec();
+        data.extend_from_slice(&3u32.to_le_bytes());
+        data.extend_from_slice(b"new");
+        data.extend_from_slice(&metadata().encode());
+        assert_eq!(Metadata::decode(&data).unwrap(), metadata());
+    }
+
+    #[test]
+    fn key_and_time_tables_must_match() {
+        let mut mismatched = metadata();
+        mismatched.times.pop();
+        assert!(mismatched.validate().is_err());
+        assert_eq!(Metadata::decode(&mismatched.encode()).unwrap_err().to_string(),
+                   "key and time tables differ in length");
+
+        mismatched.keys.clear();
+        let file = build(b"host", b"guest", &mismatched);
+        assert_eq!(error(&file), "key and time tables differ in length");
+    }
+}
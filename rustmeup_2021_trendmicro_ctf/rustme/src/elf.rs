@@ -0,0 +1,530 @@
+//! Parser for ELF64 RISC-V executables, static or static-PIE. Dynamically
+//! linked executables are refused, naming the interpreter they need
+
+use crate::mmu::{VirtAddr, Perm, Sections, PERM_READ, PERM_WRITE, PERM_EXEC};
+use std::borrow::Cow;
+use std::io;
+use std::convert::TryFrom;
+
+/// `e_machine` of RISC-V
+const EM_RISCV: u16 = 243;
+
+/// `e_type` of a static executable
+const ET_EXEC: u16 = 2;
+
+/// `e_type` of a position independent executable
+const ET_DYN: u16 = 3;
+
+/// `e_flags` of code using compressed instructions
+const EF_RISCV_RVC: u32 = 0x1;
+
+/// `e_flags` bits giving the floating point ABI, zero for soft float
+const EF_RISCV_FLOAT_ABI: u32 = 0x6;
+
+/// `e_flags` of code for the RV32E/RV64E base with 16 registers
+const EF_RISCV_RVE: u32 = 0x8;
+
+/// Address position independent executables are loaded at
+pub const PIE_BASE: usize = 0x100000;
+
+/// Loadable segment
+const PT_LOAD: u32 = 1;
+
+/// Segment holding the dynamic table
+const PT_DYNAMIC: u32 = 2;
+
+/// Segment naming the program interpreter
+const PT_INTERP: u32 = 3;
+
+/// Segment holding the program headers themselves
+const PT_PHDR: u32 = 6;
+
+/// Dynamic table tags
+const DT_NULL:     u64 = 0;
+const DT_PLTRELSZ: u64 = 2;
+const DT_SYMTAB:   u64 = 6;
+const DT_RELA:     u64 = 7;
+const DT_RELASZ:   u64 = 8;
+const DT_RELAENT:  u64 = 9;
+const DT_REL:      u64 = 17;
+const DT_JMPREL:   u64 = 23;
+
+/// Relocation types
+const R_RISCV_NONE:      u32 = 0;
+const R_RISCV_64:        u32 = 2;
+const R_RISCV_RELATIVE:  u32 = 3;
+const R_RISCV_JUMP_SLOT: u32 = 5;
+
+/// Size of an ELF64 symbol table entry
+const SYM_SIZE: usize = 24;
+
+/// Segment flags
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+const PF_R: u32 = 1 << 2;
+
+/// Guest page size
+pub const PAGE_SIZE: usize = 4096;
+
+/// A parsed executable, ready to be loaded with `Mmu::load_image`
+pub struct Elf {
+    /// Entry point
+    pub entry: VirtAddr,
+
+    /// Loadable segments, with file offsets relative to the start of the image
+    pub segments: Vec<Sections>,
+
+    /// Address of the program headers once loaded, if a segment maps them
+    pub phdr: Option<VirtAddr>,
+
+    /// Size of one program header
+    pub phent: usize,
+
+    /// Number of program headers
+    pub phnum: usize,
+
+    /// Amount the image was moved from its link addresses, zero unless it is
+    /// position independent. All addresses here already include it
+    pub base: usize,
+
+    /// File offset and size of the dynamic table
+    dynamic: Option<(usize, usize)>,
+}
+
+/// Build an error for a malformed or unsupported executable
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Read `N` bytes at `off`
+fn bytes<const N: usize>(image: &[u8], off: usize) -> io::Result<[u8; N]> {
+    off.checked_add(N).and_then(|end| image.get(off..end))
+        .map(|x| <[u8; N]>::try_from(x).unwrap())
+        .ok_or_else(|| invalid("truncated ELF"))
+}
+
+fn u16_at(image: &[u8], off: usize) -> io::Result<u16> {
+    bytes(image, off).map(u16::from_le_bytes)
+}
+
+fn u32_at(image: &[u8], off: usize) -> io::Result<u32> {
+    bytes(image, off).map(u32::from_le_bytes)
+}
+
+/// Read a `u64` which is used as an address, size or file offset
+fn usize_at(image: &[u8], off: usize) -> io::Result<usize> {
+    let val = bytes(image, off).map(u64::from_le_bytes)?;
+    usize::try_from(val).map_err(|_| invalid("ELF value out of range"))
+}
+
+/// Number of bytes at the start of `image` used by the little endian ELF64
+/// file there, of any machine. Anything past this was appended to the file.
+/// Returns `None` if `image` does not start with such a file
+pub fn file_size(image: &[u8]) -> Option<usize> {
+    if image.get(..4) != Some(b"\x7fELF") || image.get(4) != Some(&2) ||
+            image.get(5) != Some(&1) {
+        return None;
+    }
+
+    let phoff = usize_at(image, 0x20).ok()?;
+    let shoff = usize_at(image, 0x28).ok()?;
+    let phent = u16_at(image, 0x36).ok()? as usize;
+    let phnum = u16_at(image, 0x38).ok()? as usize;
+    let shent = u16_at(image, 0x3a).ok()? as usize;
+    let shnum = u16_at(image, 0x3c).ok()? as usize;
+
+    let mut end = phoff.checked_add(phent * phnum)?
+        .max(shoff.checked_add(shent * shnum)?);
+    for idx in 0..phnum {
+        let off = idx.checked_mul(phent)?.checked_add(phoff)?;
+        let p_offset = usize_at(image, off.checked_add(0x08)?).ok()?;
+        let p_filesz = usize_at(image, off.checked_add(0x20)?).ok()?;
+        end = end.max(p_offset.checked_add(p_filesz)?);
+    }
+
+    Some(end)
+}
+
+/// Section type without file contents
+const SHT_NOBITS: u32 = 8;
+
+/// Get the contents of the section `name` of the ELF64 file in `image`.
+/// Returns `None` if there's no such section or it has no file contents
+pub fn section<'a>(image: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    file_size(image)?;
+
+    let shoff = usize_at(image, 0x28).ok()?;
+    let shent = u16_at(image, 0x3a).ok()? as usize;
+    let shnum = u16_at(image, 0x3c).ok()? as usize;
+    let shstrndx = u16_at(image, 0x3e).ok()? as usize;
+    if shent < 0x40 {
+        return None;
+    }
+
+    // Contents of the section header at `idx` as (name, type, data)
+    let header = |idx: usize| -> Option<(usize, u32, &'a [u8])> {
+        let off = shoff.checked_add(idx.checked_mul(shent)?)?;
+        let sh_name   = u32_at(image, off).ok()? as usize;
+        let sh_type   = u32_at(image, off + 0x04).ok()?;
+        let sh_offset = usize_at(image, off + 0x18).ok()?;
+        let sh_size   = usize_at(image, off + 0x20).ok()?;
+        let data = if sh_type == SHT_NOBITS {
+            &[]
+        } else {
+            image.get(sh_offset..sh_offset.checked_add(sh_size)?)?
+        };
+        Some((sh_name, sh_type, data))
+    };
+
+    let (_, _, names) = header(shstrndx)?;
+    (0..shnum).filter_map(header).find(|&(sh_name, sh_type, _)| {
+        sh_type != SHT_NOBITS && names.get(sh_name..)
+            .and_then(|x| x.split(|&b| b == 0).next()) == Some(name.as_bytes())
+    }).map(|(_, _, data)| data)
+}
+
+impl Elf {
+    /// Parse the headers of the executable in `image`, placing position
+    /// independent executables at `PIE_BASE`. Dynamically linked executables
+    /// are refused, their loader needs `mmap` and the C and F extensions,
+    /// none of which are emulated
+    pub fn parse(image: &[u8]) -> io::Result<Self> {
+        if image.get(..4) != Some(b"\x7fELF") {
+            return Err(invalid("not an ELF file"));
+        }
+        if image.get(4) != Some(&2) || image.get(5) != Some(&1) {
+            return Err(invalid("not a little endian ELF64 file"));
+        }
+        if u16_at(image, 0x12)? != EM_RISCV {
+            return Err(invalid("not a RISC-V executable"));
+        }
+        let e_type = u16_at(image, 0x10)?;
+        if e_type != ET_EXEC && e_type != ET_DYN {
+            return Err(invalid("not an executable"));
+        }
+
+        // Only RV64I is emulated, refuse code which would hit an unhandled
+        // opcode instead of running it
+        let e_flags = u32_at(image, 0x30)?;
+        if e_flags & EF_RISCV_RVC != 0 {
+            return Err(invalid("compressed instructions (RVC) are not \
+                                supported, build with -march=rv64i"));
+        }
+        if e_flags & EF_RISCV_FLOAT_ABI != 0 {
+            return Err(invalid("hardware floating point ABIs are not \
+                                supported, build with -mabi=lp64"));
+        }
+        if e_flags & EF_RISCV_RVE != 0 {
+            return Err(invalid("the RVE base ISA is not supported"));
+        }
+
+        let entry = VirtAddr(usize_at(image, 0x18)?);
+        let phoff = usize_at(image, 0x20)?;
+        let phent = u16_at(image, 0x36)? as usize;
+        let phnum = u16_at(image, 0x38)? as usize;
+        if phent < 0x38 {
+            return Err(invalid("program headers too small"));
+        }
+
+        let phend = phnum.checked_mul(phent).and_then(|x| x.checked_add(phoff))
+            .ok_or_else(|| invalid("program headers out of range"))?;
+
+        let mut segments = Vec::new();
+        let mut phdr = None;
+        let mut dynamic = None;
+        for idx in 0..phnum {
+            let off = phoff + idx * phent;
+
+            let p_type   = u32_at(image, off)?;
+            let p_flags  = u32_at(image, off + 0x04)?;
+            let p_offset = usize_at(image, off + 0x08)?;
+            let p_vaddr  = usize_at(image, off + 0x10)?;
+            let p_filesz = usize_at(image, off + 0x20)?;
+            let p_memsz  = usize_at(image, off + 0x28)?;
+
+            if p_type == PT_PHDR {
+                phdr = Some(VirtAddr(p_vaddr));
+            }
+            if p_type == PT_INTERP {
+                let path = p_offset.checked_add(p_filesz)
+                    .and_then(|end| image.get(p_offset..end))
+                    .and_then(|x| x.split(|&b| b == 0).next())
+                    .and_then(|x| std::str::from_utf8(x).ok())
+                    .ok_or_else(|| invalid("bad interpreter path"))?;
+                return Err(io::Error::new(io::ErrorKind::Unsupported, format!(
+                    "dynamic linking is unsupported, the guest needs the \
+                     interpreter {}; link it with -static or -static-pie", path)));
+            }
+            if p_type == PT_DYNAMIC {
+                dynamic = Some((p_offset, p_filesz));
+            }
+            if p_type != PT_LOAD || p_memsz == 0 {
+                continue;
+            }
+            if p_filesz > p_memsz ||
+                    p_offset.checked_add(p_filesz).is_none_or(|x| x > image.len()) {
+                return Err(invalid("segment out of range"));
+            }
+            if p_vaddr.checked_add(p_memsz).is_none() {
+                return Err(invalid("segment out of range"));
+            }
+
+            // Without a PT_PHDR the headers are found in the segment holding
+            // them in the file
+            if phdr.is_none() && p_offset <= phoff && phend <= p_offset + p_filesz {
+                phdr = Some(VirtAddr(p_vaddr + (phoff - p_offset)));
+            }
+
+            let flag = |bit, perm| if (p_flags & bit) != 0 { perm } else { 0 };
+            segments.push(Sections {
+                file_offset: p_offset,
+                virt_addr:   VirtAddr(p_vaddr),
+                file_size:   p_filesz,
+                mem_size:    p_memsz,
+                permissions: Perm(flag(PF_R, PERM_READ) | flag(PF_W, PERM_WRITE) |
+                                  flag(PF_X, PERM_EXEC)),
+            });
+        }
+
+        if segments.is_empty() {
+            return Err(invalid("no loadable segments"));
+        }
+
+        // Move position independent images from their link addresses, which
+        // usually start at zero, up to `PIE_BASE`
+        let base = if e_type == ET_DYN {
+            let lowest = segments.iter().map(|x| x.virt_addr.0).min().unwrap();
+            PIE_BASE.checked_sub(lowest & !(PAGE_SIZE - 1))
+                .ok_or_else(|| invalid("segments above the load base"))?
+        } else {
+            0
+        };
+        for segment in &mut segments {
+            segment.virt_addr = VirtAddr(segment.virt_addr.0 + base);
+        }
+
+        Ok(Elf {
+            entry:  VirtAddr(entry.0.wrapping_add(base)),
+            phdr:   phdr.map(|x| VirtAddr(x.0 + base)),
+            segments, phent, phnum, base, dynamic,
+        })
+    }
+
+    /// Translate the link address `addr` to an offset into the file, `None`
+    /// if no segment holds file contents there
+    fn file_offset(&self, addr: u64) -> Option<usize> {
+        let addr = usize::try_from(addr).ok()?.checked_add(self.base)?;
+        self.segments.iter().find(|x| {
+            addr >= x.virt_addr.0 && addr - x.virt_addr.0 < x.file_size
+        }).map(|x| x.file_offset + (addr - x.virt_addr.0))
+    }
+
+    /// Apply the dynamic relocations of `image` for the load base, returning
+    /// the image to load. Images without relocations are returned as is
+    pub fn relocate<'a>(&self, image: &'a [u8]) -> io::Result<Cow<'a, [u8]>> {
+        let (dyn_off, dyn_size) = match self.dynamic {
+            Some(dynamic) => dynamic,
+            None => return Ok(Cow::Borrowed(image)),
+        };
+
+        // Tables must lie in the file, which also keeps the offsets of their
+        // fields from overflowing
+        let dyn_end = dyn_off.checked_add(dyn_size).filter(|&x| x <= image.len())
+            .ok_or_else(|| invalid("dynamic table out of range"))?;
+
+        // Collect the tables from the dynamic section
+        let mut rela = None;
+        let mut relasz = 0;
+        let mut relaent = 24;
+        let mut jmprel = None;
+        let mut pltrelsz = 0;
+        let mut symtab = None;
+        for off in (dyn_off..dyn_end).step_by(16) {
+            let tag = bytes(image, off).map(u64::from_le_bytes)?;
+            let val = bytes(image, off + 8).map(u64::from_le_bytes)?;
+            match tag {
+                DT_NULL     => break,
+                DT_RELA     => rela = Some(val),
+                DT_RELASZ   => relasz = val as usize,
+                DT_RELAENT  => relaent = val as usize,
+                DT_JMPREL   => jmprel = Some(val),
+                DT_PLTRELSZ => pltrelsz = val as usize,
+                DT_SYMTAB   => symtab = Some(val),
+                DT_REL      => return Err(invalid("REL relocations are not supported")),
+                _ => {}
+            }
+        }
+        if relaent < 24 {
+            return Err(invalid("relocation entries too small"));
+        }
+
+        let mut out = image.to_vec();
+        let tables = [(rela, relasz), (jmprel, pltrelsz)];
+        for &(table, size) in &tables {
+            let table = match table {
+                Some(table) if size > 0 => self.file_offset(table)
+                    .ok_or_else(|| invalid("relocations out of range"))?,
+                _ => continue,
+            };
+            let end = table.checked_add(size).filter(|&x| x <= image.len())
+                .ok_or_else(|| invalid("relocations out of range"))?;
+
+            for off in (table..end).step_by(relaent) {
+                let r_offset = bytes(image, off).map(u64::from_le_bytes)?;
+                let r_info   = bytes(image, off + 8).map(u64::from_le_bytes)?;
+                let r_addend = bytes(image, off + 16).map(u64::from_le_bytes)?;
+
+                // Value of the symbol the relocation refers to
+                let sym = |image: &[u8]| -> io::Result<u64> {
+                    let sym_off = symtab.and_then(|x| self.file_offset(x))
+                        .and_then(|x| ((r_info >> 32) as usize).checked_mul(SYM_SIZE)
+                                  .and_then(|idx| x.checked_add(idx)))
+                        .filter(|&x| x < image.len())
+                        .ok_or_else(|| invalid("symbol out of range"))?;
+                    if u16_at(image, sym_off + 6)? == 0 {
+                        return Err(invalid("undefined symbol in a static executable"));
+                    }
+                    Ok(bytes(image, sym_off + 8).map(u64::from_le_bytes)?
+                        .wrapping_add(self.base as u64))
+                };
+
+                let val = match r_info as u32 {
+                    R_RISCV_NONE      => continue,
+                    R_RISCV_RELATIVE  => (self.base as u64).wrapping_add(r_addend),
+                    R_RISCV_64        => sym(image)?.wrapping_add(r_addend),
+                    R_RISCV_JUMP_SLOT => sym(image)?,
+                    kind => {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                            format!("unsupported relocation type {}", kind)));
+                    }
+                };
+
+                let target = self.file_offset(r_offset)
+                    .filter(|&x| x + 8 <= out.len())
+                    .ok_or_else(|| invalid("relocation target out of range"))?;
+                out[target..target + 8].copy_from_slice(&val.to_le_bytes());
+            }
+        }
+
+        Ok(Cow::Owned(out))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An ELF header of a RISC-V executable with `e_flags`
+    fn header(e_flags: u32) -> Vec<u8> {
+        let mut image = vec![0u8; 0x40];
+        image[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        image[0x10..0x12].copy_from_slice(&ET_EXEC.to_le_bytes());
+        image[0x12..0x14].copy_from_slice(&EM_RISCV.to_le_bytes());
+        image[0x30..0x34].copy_from_slice(&e_flags.to_le_bytes());
+        image
+    }
+
+    #[test]
+    fn unsupported_isa_flags_are_rejected() {
+        for (flags, msg) in [(EF_RISCV_RVC, "RVC"),
+                             (0x2, "floating point"),
+                             (0x4, "floating point"),
+                             (0x5, "RVC"),
+                             (EF_RISCV_RVE, "RVE")] {
+            let err = Elf::parse(&header(flags)).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains(msg), "{:#x}: {}", flags, err);
+        }
+
+        // Plain RV64I gets past the flags, to the missing program headers
+        let err = Elf::parse(&header(0)).err().unwrap();
+        assert!(!err.to_string().contains("supported"), "{}", err);
+    }
+
+    #[test]
+    fn file_size_of_hostile_headers_is_none() {
+        let mut image = header(0);
+        image.resize(0x80, 0);
+        image[0x36..0x38].copy_from_slice(&0x38u16.to_le_bytes());
+        image[0x38..0x3a].copy_from_slice(&1u16.to_le_bytes());
+        image[0x48..0x50].copy_from_slice(&0x10u64.to_le_bytes());
+        image[0x60..0x68].copy_from_slice(&0x70u64.to_le_bytes());
+
+        image[0x20..0x28].copy_from_slice(&0x40u64.to_le_bytes());
+        assert_eq!(file_size(&image), Some(0x80));
+
+        // Program headers at the very end of the address space, too small
+        // for the fields read out of them
+        for (phent, phoff) in [(0u16, u64::MAX), (0, u64::MAX - 4), (4, u64::MAX - 4),
+                               (0x10, u64::MAX - 0x18)] {
+            image[0x36..0x38].copy_from_slice(&phent.to_le_bytes());
+            image[0x20..0x28].copy_from_slice(&phoff.to_le_bytes());
+            assert_eq!(file_size(&image), None);
+        }
+        image[0x36..0x38].copy_from_slice(&0x38u16.to_le_bytes());
+
+        // Truncated in the middle of the program headers
+        image[0x38..0x3a].copy_from_slice(&1u16.to_le_bytes());
+        image[0x20..0x28].copy_from_slice(&0x40u64.to_le_bytes());
+        assert_eq!(file_size(&image[..0x60]), None);
+    }
+
+    /// A static-PIE of 0x200 bytes loaded whole, with its dynamic table at
+    /// `dyn_off` holding the `(tag, value)` pairs of `dynamic` if it fits
+    fn pie(dyn_off: u64, dyn_size: u64, dynamic: &[(u64, u64)]) -> Vec<u8> {
+        let mut image = header(0);
+        image.resize(0x200, 0);
+        image[0x10..0x12].copy_from_slice(&ET_DYN.to_le_bytes());
+        image[0x20..0x28].copy_from_slice(&0x40u64.to_le_bytes());
+        image[0x36..0x38].copy_from_slice(&0x38u16.to_le_bytes());
+        image[0x38..0x3a].copy_from_slice(&2u16.to_le_bytes());
+
+        let mut phdr = |off: usize, p_type: u32, p_offset: u64, p_size: u64| {
+            image[off..off + 4].copy_from_slice(&p_type.to_le_bytes());
+            image[off + 0x08..off + 0x10].copy_from_slice(&p_offset.to_le_bytes());
+            image[off + 0x10..off + 0x18].copy_from_slice(&p_offset.to_le_bytes());
+            image[off + 0x20..off + 0x28].copy_from_slice(&p_size.to_le_bytes());
+            image[off + 0x28..off + 0x30].copy_from_slice(&p_size.to_le_bytes());
+        };
+        phdr(0x40, PT_LOAD, 0, 0x200);
+        phdr(0x78, PT_DYNAMIC, dyn_off, dyn_size);
+
+        for (idx, &(tag, val)) in dynamic.iter().enumerate() {
+            let off = 0x100 + idx * 16;
+            image[off..off + 8].copy_from_slice(&tag.to_le_bytes());
+            image[off + 8..off + 16].copy_from_slice(&val.to_le_bytes());
+        }
+        image
+    }
+
+    #[test]
+    fn relocation_tables_out_of_range_are_rejected() {
+        let relocate = |image: &[u8]| {
+            Elf::parse(image).unwrap().relocate(image).err().unwrap().kind()
+        };
+
+        // Dynamic tables wrapping around or running past the file
+        assert_eq!(relocate(&pie(u64::MAX - 8, 0x100, &[])),
+                   io::ErrorKind::InvalidData);
+        assert_eq!(relocate(&pie(0x100, 0x200, &[])), io::ErrorKind::InvalidData);
+
+        // Relocation tables of any size
+        for (tag, size) in [(DT_RELASZ, u64::MAX), (DT_PLTRELSZ, u64::MAX - 0x17f),
+                            (DT_RELASZ, 0x81)] {
+            let table = if tag == DT_RELASZ { DT_RELA } else { DT_JMPREL };
+            let image = pie(0x100, 0x40, &[(table, 0x180), (tag, size)]);
+            assert_eq!(relocate(&image), io::ErrorKind::InvalidData);
+        }
+
+        // A symbol index past the end of the address space
+        let mut image = pie(0x100, 0x40, &[(DT_RELA, 0x180), (DT_RELASZ, 24),
+                                           (DT_SYMTAB, 0x1c0)]);
+        image[0x188..0x190].copy_from_slice(
+            &(((u32::MAX as u64) << 32) | R_RISCV_64 as u64).to_le_bytes());
+        assert_eq!(relocate(&image), io::ErrorKind::InvalidData);
+
+        let image = pie(0x100, 0x40, &[(DT_RELA, 0x180), (DT_RELASZ, 0)]);
+        let elf = Elf::parse(&image).unwrap();
+        assert!(matches!(elf.relocate(&image), Ok(Cow::Owned(_))));
+    }
+}
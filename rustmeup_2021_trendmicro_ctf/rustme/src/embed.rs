@@ -0,0 +1,45 @@
+//! Locating the guest executable appended to the host
+//!
+//! A guest is shipped by appending its ELF to the host executable, optionally
+//! followed by a trailer of the guest's `u64` file offset and `TRAILER_MAGIC`.
+//! Without a trailer the guest is found by scanning past the end of the
+//! host's own ELF. Bundles built by the `bundle` tool have their own trailer,
+//! see the `bundle` module.
+
+use crate::elf::{self, Elf};
+use std::convert::TryFrom;
+
+/// Magic at the very end of a file with a guest trailer
+pub const TRAILER_MAGIC: &[u8; 8] = b"RUSTMEGU";
+
+/// Size of the trailer, the guest offset followed by the magic
+pub const TRAILER_SIZE: usize = 16;
+
+/// Find the guest executable in `file`, returning its offset and bytes
+pub fn find_guest(file: &[u8]) -> Option<(usize, &[u8])> {
+    // An explicit trailer says exactly where the guest is
+    if let Some(trailer) = file.len().checked_sub(TRAILER_SIZE)
+            .map(|x| &file[x..]) {
+        if &trailer[8..] == TRAILER_MAGIC {
+            let offset = usize::try_from(u64::from_le_bytes(
+                <[u8; 8]>::try_from(&trailer[..8]).unwrap())).ok()?;
+            let guest = file.get(offset..file.len() - TRAILER_SIZE)?;
+            Elf::parse(guest).ok()?;
+            return Some((offset, guest));
+        }
+    }
+
+    // Otherwise the guest is the first RISC-V executable after the host
+    let start = elf::file_size(file).unwrap_or(0);
+    if let Some(offset) = file.get(start..)?.windows(4)
+            .enumerate()
+            .filter(|(_, x)| *x == b"\x7fELF")
+            .map(|(idx, _)| start + idx)
+            .find(|&x| x > 0 && Elf::parse(&file[x..]).is_ok()) {
+        return Some((offset, &file[offset..]));
+    }
+
+    // A bare guest executable
+    Elf::parse(file).ok()?;
+    Some((0, file))
+}
@@ -1,7 +1,22 @@
 //! A 64-bit RISC-V RV64i interpreter
 
 use std::fmt;
-use crate::mmu::{VirtAddr, Mmu, Perm, PERM_EXEC};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+use crate::mmu::{VirtAddr, Mmu, Perm, ResetMismatch, PERM_EXEC, PERM_READ,
+                 PERM_WRITE};
+use crate::taint::{Taint, TaintLabel, TaintUse};
+use crate::snapshot;
+use crate::trace::{MemAccess, TraceHandle, TraceLog, Tracer};
+use crate::shadow::{Frame, ShadowStack};
+use crate::region::Location;
+use crate::elf::Elf;
+use crate::firmware::Firmware;
+use crate::symbols::{CodeLocation, Symbols};
+use crate::vfs::Vfs;
+use crate::input::{Input, InputHandle};
+use std::sync::{Arc, Mutex};
 
 
 /// An J-type instuctions
@@ -25,7 +40,7 @@ impl From<u32> for Jtype {
         let imm = ((imm as i32) << 11) >> 11;
  
         Jtype{
-            imm: imm,
+            imm,
             rd: Register::from((inst >> 7) & 0b11111), 
         }
     }
@@ -54,7 +69,7 @@ impl From<u32> for Btype {
         let imm = ((imm as i32) << 19) >> 19;
  
         Btype{
-            imm:     imm,
+            imm,
             rs2:    Register::from((inst >> 20) & 0b11111), 
             rs1:    Register::from((inst >> 15) & 0b11111), 
             funct3: (inst >> 12) & 0b111,
@@ -104,7 +119,7 @@ impl From<u32> for Stype {
         let imm = (imm115 << 5) | imm40 ;
         let imm = ((imm as i32) << 20) >> 20 ;
         Stype{
-            imm:     imm,
+            imm,
             rs2:     Register::from((inst >> 20) & 0b11111),
             rs1:     Register::from((inst >> 15) & 0b11111),
             funct3:  (inst >> 12) & 0b111,
@@ -129,7 +144,7 @@ impl From<u32> for Itype {
         let imm = (inst as i32) >> 20;
  
         Itype{
-            imm:     imm,
+            imm,
             rs1:     Register::from((inst >> 15) & 0b11111),
             funct3: (inst >> 12) & 0b111,
             rd:     Register::from((inst >> 7) & 0b11111), 
@@ -156,13 +171,42 @@ impl From<u32> for Utype {
     }
 }
 
+/// Register, taint, shadow stack and file state saved by a checkpoint
+type CheckpointState = ([u64; 33], Option<Taint>, Option<ShadowStack>, Vfs);
+
 /// All the state of the emulated system
 pub struct Emulator {
     /// Memory for the emulator
     pub memory: Mmu,
+
+    /// Files and descriptors of the guest
+    pub vfs: Vfs,
+
+    /// Stream read by the guest's stdin, shared with forks. Bytes consumed
+    /// stay consumed across resets and restores
+    pub stdin: InputHandle,
     
     /// All
     registers: [u64; 33],
+
+    /// Register taint and label table, `None` if taint tracking is off
+    taint: Option<Taint>,
+
+    /// Shadow call stack, `None` if control flow checking is off
+    shadow: Option<ShadowStack>,
+
+    /// Emulator state of every checkpoint in `memory`
+    checkpoints: Vec<CheckpointState>,
+
+    /// Tracer of guest loads and stores, `None` if tracing is off
+    tracer: Option<Tracer>,
+
+    /// Symbols and line numbers of the guest, shared with forks
+    symbols: Option<Arc<Symbols>>,
+
+    /// PC of the last instruction `run` executed. Watchpoint hits pending
+    /// when `run` is entered were made by it or the system call it made
+    last_pc: u64,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -182,16 +226,90 @@ pub enum VmExit {
     /// a read or write memory request overflowed the 
     AddressIntegerOverflow,
 
-    /// The address requested was not in bounds to the guest memory space
-    AddressMiss(VirtAddr, usize),
+    /// The address requested was not in bounds to the guest memory space.
+    /// Faults also carry where the address lies among the named regions
+    AddressMiss(VirtAddr, usize, Option<Location>),
 
     /// A read of `VirtAddr` is failed due to missing permission
-    ReadFault(VirtAddr),
+    ReadFault(VirtAddr, Option<Location>),
 
     /// An write of `VirtAddr` is failed due to missing permission
-    WriteFault(VirtAddr),
+    WriteFault(VirtAddr, Option<Location>),
+
+    /// An access of `VirtAddr` hit the guard region below a stack
+    StackOverflow(VirtAddr),
+
+    /// The instruction at `pc` touched the watched address `addr` with an
+    /// access of `kind`. The instruction has completed, so calling `run`
+    /// again resumes after it. For an `ecall` the access may have been made
+    /// by its system call
+    Watchpoint {
+        addr: VirtAddr,
+        kind: Perm,
+        pc:   VirtAddr,
+    },
+
+    /// The jump at `pc` to `target` broke the shadow call stack. `expected`
+    /// is the return address of the innermost call for a bad return, `None`
+    /// for a return without a call or an indirect jump out of code
+    ControlFlowHijack {
+        pc:       VirtAddr,
+        target:   VirtAddr,
+        expected: Option<VirtAddr>,
+    },
+
+    /// The `ecall` at `pc` made system call `num`, which has no handler
+    UnknownSyscall {
+        num: u64,
+        pc:  VirtAddr,
+    },
 
+}
 
+impl fmt::Display for VmExit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Append the region an address is in, if known
+        let at = |f: &mut fmt::Formatter<'_>, loc: &Option<Location>| {
+            match loc {
+                Some(loc) => write!(f, " ({})", loc),
+                None      => Ok(()),
+            }
+        };
+
+        match self {
+            VmExit::AddressMiss(addr, size, loc) => {
+                write!(f, "access of {} bytes at {:#x} out of bounds",
+                       size, addr.0)?;
+                at(f, loc)
+            }
+            VmExit::ReadFault(addr, loc) => {
+                write!(f, "read fault at {:#x}", addr.0)?;
+                at(f, loc)
+            }
+            VmExit::WriteFault(addr, loc) => {
+                write!(f, "write fault at {:#x}", addr.0)?;
+                at(f, loc)
+            }
+            VmExit::StackOverflow(addr) => {
+                write!(f, "stack overflow touching {:#x}", addr.0)
+            }
+            VmExit::Watchpoint { addr, kind, pc } => {
+                write!(f, "watchpoint {:#x} {} at pc {:#x}", addr.0, kind, pc.0)
+            }
+            VmExit::ControlFlowHijack { pc, target, expected: Some(exp) } => {
+                write!(f, "return at {:#x} to {:#x} instead of {:#x}",
+                       pc.0, target.0, exp.0)
+            }
+            VmExit::ControlFlowHijack { pc, target, expected: None } => {
+                write!(f, "jump at {:#x} to {:#x} outside of known code",
+                       pc.0, target.0)
+            }
+            VmExit::UnknownSyscall { num, pc } => {
+                write!(f, "unknown system call {} at {:#x}", num, pc.0)
+            }
+            _ => write!(f, "{:?}", self),
+        }
+    }
 }
 
 impl fmt::Display for Emulator {
@@ -239,7 +357,13 @@ pc   {:016x}"#,
     self.reg(Register::T5),
     self.reg(Register::T6),
     self.reg(Register::Pc),
-)
+)?;
+
+        // Name the code PC is in when the guest has symbols
+        if let Some(symbols) = &self.symbols {
+            write!(f, " {}", symbols.locate(VirtAddr(self.reg(Register::Pc) as usize)))?;
+        }
+        Ok(())
     }
 }
 
@@ -296,25 +420,326 @@ impl Emulator {
     pub fn new(size: usize) -> Self {
         Emulator {
             memory: Mmu::new(size),
+            vfs: Vfs::new(),
+            stdin: Arc::new(Mutex::new(Input::stdin())),
             registers: [0; 33],
+            taint: None,
+            shadow: None,
+            checkpoints: Vec::new(),
+            tracer: None,
+            symbols: None,
+            last_pc: 0,
         }
     }
     /// Fork emulator into a new emulator which will diff from the original
     pub fn fork(&self) -> Self{
         Emulator {
             memory: self.memory.fork(),
-            registers: self.registers.clone(),
+            vfs: self.vfs.clone(),
+            stdin: self.stdin.clone(),
+            registers: self.registers,
+            taint: self.taint.clone(),
+            shadow: self.shadow.clone(),
+            checkpoints: Vec::new(),
+            tracer: None,
+            symbols: self.symbols.clone(),
+            last_pc: self.last_pc,
         }
     }
 
     /// Reset tje state of `self` to `other`, assuming that `self` is 
-    /// foked off of `other`. It it is not, the results are invalid.
-    pub fn reset(&mut self, other: &Self) {
+    /// foked off of `other`. It it is not, the results are invalid. With
+    /// reset verification on, returns the memory blocks left different
+    pub fn reset(&mut self, other: &Self) -> Result<(), Vec<ResetMismatch>> {
         // Reset memory state
-        self.memory.reset(&other.memory);
+        let memory = self.memory.reset(&other.memory);
 
         // Reset regesiter state
         self.registers = other.registers; 
+        self.last_pc = other.last_pc;
+
+        // Reset taint state
+        self.taint.clone_from(&other.taint);
+
+        // Reset shadow call stack
+        self.shadow.clone_from(&other.shadow);
+
+        // Reset files and descriptors
+        self.vfs.clone_from(&other.vfs);
+
+        // Checkpoints are all newer than `other`
+        self.checkpoints.clear();
+
+        memory
+    }
+
+    /// Take a checkpoint of the registers and memory, returning its depth.
+    /// Checkpoints nest, so a harness can checkpoint at several points of
+    /// execution and `restore` any of them
+    pub fn checkpoint(&mut self) -> usize {
+        self.checkpoints.push((self.registers, self.taint.clone(),
+                               self.shadow.clone(), self.vfs.clone()));
+        self.memory.checkpoint()
+    }
+
+    /// Number of checkpoints currently on the stack
+    pub fn checkpoint_depth(&self) -> usize {
+        self.checkpoints.len()
+    }
+
+    /// Restore the checkpoint at `depth`, dropping all newer checkpoints.
+    /// The checkpoint at `depth` is kept and can be restored again
+    pub fn restore(&mut self, depth: usize) -> Option<()> {
+        let (registers, taint, shadow, vfs) =
+            self.checkpoints.get(depth)?.clone();
+
+        self.memory.restore(depth)?;
+        self.checkpoints.truncate(depth + 1);
+        self.registers = registers;
+        self.taint = taint;
+        self.shadow = shadow;
+        self.vfs = vfs;
+        Some(())
+    }
+
+    /// Save the registers, memory state, files and descriptors to a snapshot
+    /// file at `path`
+    pub fn save_snapshot<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+
+        snapshot::write_header(&mut w)?;
+        for &reg in &self.registers {
+            snapshot::write_u64(&mut w, reg)?;
+        }
+        self.memory.save_snapshot(&mut w)?;
+        self.vfs.save_snapshot(&mut w)?;
+
+        w.flush()
+    }
+
+    /// Load the executable in `image` and point PC at its entry. Static
+    /// position independent executables are relocated to `elf::PIE_BASE`.
+    /// The allocator starts at the initial break, so the stack and heap follow
+    /// the image. Dynamically linked executables are refused
+    pub fn load_elf(&mut self, image: &[u8]) -> io::Result<Elf> {
+        let elf = Elf::parse(image)?;
+        let relocated = elf.relocate(image)?;
+
+        self.memory.load_image(&relocated, &elf.segments).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData,
+                           "segments do not fit in guest memory")
+        })?;
+        self.set_reg(Register::Pc, elf.entry.0 as u64);
+        self.symbols = Some(Arc::new(Symbols::parse(image, elf.base)));
+
+        Ok(elf)
+    }
+
+    /// Load the bare-metal `firmware` and point PC at its entry. Firmware has
+    /// no symbols
+    pub fn load_firmware(&mut self, firmware: &Firmware) -> io::Result<()> {
+        self.memory.load_image(&firmware.image, &firmware.segments)
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData,
+                               "firmware does not fit in guest memory")
+            })?;
+        self.set_reg(Register::Pc, firmware.entry.0 as u64);
+
+        Ok(())
+    }
+
+    /// Get the symbols of the guest, `None` if no ELF was loaded
+    pub fn symbols(&self) -> Option<&Symbols> {
+        self.symbols.as_deref()
+    }
+
+    /// Use `symbols` to describe guest addresses, eg. after loading a
+    /// snapshot, which does not hold them
+    pub fn set_symbols(&mut self, symbols: Arc<Symbols>) {
+        self.symbols = Some(symbols);
+    }
+
+    /// Describe the code at `addr` by symbol and line, `None` without symbols
+    pub fn locate_code(&self, addr: VirtAddr) -> Option<CodeLocation<'_>> {
+        self.symbols.as_ref().map(|x| x.locate(addr))
+    }
+
+    /// Create an emulator from the snapshot file at `path`
+    pub fn load_snapshot<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut r = BufReader::new(File::open(path)?);
+
+        snapshot::read_header(&mut r)?;
+        let mut registers = [0u64; 33];
+        for reg in registers.iter_mut() {
+            *reg = snapshot::read_u64(&mut r)?;
+        }
+
+        Ok(Emulator {
+            memory: Mmu::load_snapshot(&mut r)?,
+            vfs: Vfs::load_snapshot(&mut r)?,
+            stdin: Arc::new(Mutex::new(Input::stdin())),
+            registers,
+            taint: None,
+            shadow: None,
+            checkpoints: Vec::new(),
+            tracer: None,
+            symbols: None,
+            last_pc: 0,
+        })
+    }
+
+    /// Start tracking taint of guest input through registers and memory
+    pub fn enable_taint(&mut self) {
+        self.memory.enable_taint();
+        if self.taint.is_none() {
+            self.taint = Some(Taint::new());
+        }
+    }
+
+    /// Get the taint state, `None` if taint tracking is off
+    pub fn taint(&self) -> Option<&Taint> {
+        self.taint.as_ref()
+    }
+
+    /// Get the mutable taint state, `None` if taint tracking is off
+    pub fn taint_mut(&mut self) -> Option<&mut Taint> {
+        self.taint.as_mut()
+    }
+
+    /// Mark `size` bytes at `addr` as the next bytes of the input stream.
+    /// Does nothing if taint tracking is off
+    pub fn taint_input(&mut self, addr: VirtAddr, size: usize) {
+        if let Some(taint) = &mut self.taint {
+            for offset in 0..size {
+                let label = taint.next_input();
+                self.memory.set_taint(VirtAddr(addr.0 + offset), 1, label);
+            }
+        }
+    }
+
+    /// Start checking calls and returns against a shadow call stack. Calls
+    /// made before this are unknown, so enable it before the guest runs
+    pub fn enable_shadow_stack(&mut self) {
+        if self.shadow.is_none() {
+            self.shadow = Some(ShadowStack::new());
+        }
+    }
+
+    /// Calls the guest has not returned from yet, innermost last. `None` if
+    /// control flow checking is off
+    pub fn call_stack(&self) -> Option<&[Frame]> {
+        self.shadow.as_ref().map(|x| x.frames())
+    }
+
+    /// Check the jump by the instruction at `pc` linking into `rd` and
+    /// jumping through `rs1` (`None` for a direct jump) to `target`
+    fn check_jump(&mut self, pc: u64, rd: Register, rs1: Option<Register>,
+                  target: u64) -> Result<(), VmExit> {
+        let shadow = match self.shadow.as_mut() {
+            Some(shadow) => shadow,
+            None => return Ok(()),
+        };
+        let hijack = |expected: Option<Frame>| VmExit::ControlFlowHijack {
+            pc:       VirtAddr(pc as usize),
+            target:   VirtAddr(target as usize),
+            expected: expected.map(|x| x.ret),
+        };
+
+        // Calls and returns follow the return address stack hints of the
+        // spec: `ra` and `t0` are link registers, jumping through one is a
+        // return unless it is also the register being linked
+        let is_link = |reg| reg == Register::Ra || reg == Register::T0;
+        let call = is_link(rd);
+        let ret = rs1.is_some_and(|rs1| is_link(rs1) && rs1 != rd);
+
+        // A return must go back to where the innermost call came from
+        if ret {
+            shadow.ret(VirtAddr(target as usize)).map_err(hijack)?;
+        }
+
+        if call {
+            shadow.call(VirtAddr(pc as usize), VirtAddr(pc as usize + 4));
+        }
+
+        // Indirect jumps and calls must land in executable memory
+        if rs1.is_some() {
+            let exec = self.memory.perms(VirtAddr(target as usize))
+                .is_some_and(|x| (x.0 & PERM_EXEC) != 0);
+            if !exec {
+                return Err(hijack(None));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Send every guest load and store to `sink`, replacing any previous
+    /// tracer and its filters
+    pub fn set_trace_sink(&mut self, sink: TraceHandle) {
+        self.tracer = Some(Tracer::new(sink));
+    }
+
+    /// Print every guest load and store to `out`, naming the code which made
+    /// it when the guest has symbols. Replaces any previous tracer
+    pub fn set_trace_log<W: Write + Send + 'static>(&mut self, out: W) {
+        let log = TraceLog::new(out, self.symbols.clone());
+        self.set_trace_sink(Arc::new(Mutex::new(log)));
+    }
+
+    /// Only trace accesses touching `size` bytes at `addr` or one of the
+    /// other filtered ranges. Returns `None` if tracing is off
+    pub fn add_trace_filter(&mut self, addr: VirtAddr, size: usize)
+            -> Option<()> {
+        self.tracer.as_mut()?.add_filter(addr, size);
+        Some(())
+    }
+
+    /// Stop tracing
+    pub fn clear_trace(&mut self) {
+        self.tracer = None;
+    }
+
+    /// Trace an access of `size` bytes at `addr` by the instruction at `pc`
+    fn trace(&self, pc: u64, addr: VirtAddr, size: usize, value: u64,
+             kind: u8) {
+        if let Some(tracer) = &self.tracer {
+            let mask = if size < 8 { (1u64 << (size * 8)) - 1 } else { !0 };
+            tracer.trace(&MemAccess {
+                pc: VirtAddr(pc as usize), addr, size, value: value & mask,
+                kind: Perm(kind),
+            });
+        }
+    }
+
+    /// Propagate the taint of `srcs` into `rd`
+    fn taint_flow(&mut self, rd: Register, srcs: &[Register]) {
+        if let Some(taint) = &mut self.taint {
+            taint.flow(rd, srcs);
+        }
+    }
+
+    /// Record a use of `srcs` by the instruction at `pc` if they are tainted
+    fn taint_record(&mut self, pc: u64, kind: TaintUse, srcs: &[Register]) {
+        if let Some(taint) = &mut self.taint {
+            taint.record(VirtAddr(pc as usize), kind, srcs);
+        }
+    }
+
+    /// Give `rd` the union of the taint of `size` bytes at `addr`
+    fn taint_load(&mut self, rd: Register, addr: VirtAddr, size: usize) {
+        if let Some(taint) = &mut self.taint {
+            let label = self.memory.taint(addr, size)
+                .map(|x| taint.union_all(x))
+                .unwrap_or(TaintLabel::CLEAN);
+            taint.set_reg(rd, label);
+        }
+    }
+
+    /// Give `size` bytes at `addr` the taint of `rs2`
+    fn taint_store(&mut self, rs2: Register, addr: VirtAddr, size: usize) {
+        if let Some(taint) = &self.taint {
+            self.memory.set_taint(addr, size, taint.reg(rs2));
+        }
     }
 
 
@@ -341,7 +766,17 @@ impl Emulator {
 
         'next_inst: loop {
 
+            // Report watchpoints touched by the previous instruction. On
+            // entry this is an `ecall`, whose system call may have hit one
+            if let Some((addr, kind)) = self.memory.take_watch_hit() {
+                return Err(VmExit::Watchpoint {
+                    addr, kind, pc: VirtAddr(self.last_pc as usize),
+                });
+            }
+
             let pc = self.reg(Register::Pc);
+            self.last_pc = pc;
+            self.memory.set_pc(VirtAddr(pc as usize));
             
             let inst: u32 = self.memory.read_perms(VirtAddr(pc as usize), 
                                                    Perm(PERM_EXEC))?;
@@ -353,12 +788,14 @@ impl Emulator {
                 0b0110111 =>{
                     // LUI
                     let inst = Utype::from(inst);
+                    self.taint_flow(inst.rd, &[]);
                     self.set_reg(inst.rd, inst.imm as i64 as u64);
                     //println!("{:?}", inst);
                 },
                  0b0010111 =>{
                     // AUIPC
                     let inst = Utype::from(inst);
+                    self.taint_flow(inst.rd, &[]);
                     self.set_reg(inst.rd, 
                                  (inst.imm as i64 as u64).wrapping_add(pc));
                     //println!("{:?}", inst);
@@ -366,9 +803,11 @@ impl Emulator {
                 0b1101111 => {
                     //JAL
                     let inst = Jtype::from(inst);
+                    let target = pc.wrapping_add(inst.imm as i64 as u64);
+                    self.check_jump(pc, inst.rd, None, target)?;
+                    self.taint_flow(inst.rd, &[]);
                     self.set_reg(inst.rd, pc.wrapping_add(4));
-                    self.set_reg(Register::Pc,
-                                 pc.wrapping_add(inst.imm as i64 as u64));
+                    self.set_reg(Register::Pc, target);
                     continue 'next_inst;
                 },
                  0b1100111 => {
@@ -380,6 +819,11 @@ impl Emulator {
                             //JALR
                             let target = self.reg(inst.rs1).wrapping_add(
                                 inst.imm as i64 as u64);
+                            self.taint_record(pc, TaintUse::JumpTarget,
+                                              &[inst.rs1]);
+                            self.check_jump(pc, inst.rd, Some(inst.rs1),
+                                            target)?;
+                            self.taint_flow(inst.rd, &[]);
                             self.set_reg(inst.rd, pc.wrapping_add(4));
                             self.set_reg(Register::Pc, target);
                             continue 'next_inst;
@@ -394,6 +838,8 @@ impl Emulator {
 
                     let rs1 = self.reg(inst.rs1);
                     let rs2 = self.reg(inst.rs2);
+                    self.taint_record(pc, TaintUse::Branch,
+                                      &[inst.rs1, inst.rs2]);
 
                     match inst.funct3 {
                         0b000 => {
@@ -434,7 +880,7 @@ impl Emulator {
                         },
                           0b110 => {
                             // BLTU
-                            if (rs1 as u64) < (rs2 as u64) {
+                            if rs1 < rs2 {
                                 self.set_reg(Register::Pc,
                                              pc.wrapping_add(inst.imm as i64 as u64));
                                 continue 'next_inst;
@@ -443,7 +889,7 @@ impl Emulator {
                         },
                            0b111 => {
                             // BGEU
-                            if (rs1 as u64) >= (rs2 as u64) {
+                            if rs1 >= rs2 {
                                 self.set_reg(Register::Pc,
                                              pc.wrapping_add(inst.imm as i64 as u64));
                                 continue 'next_inst;
@@ -462,60 +908,50 @@ impl Emulator {
                                         .wrapping_add(inst.imm as i64 as u64) 
                                         as usize);
                     //println!("Reading from {:#x}", addr.0);
-                    match inst.funct3 {
+                    let size = 1 << (inst.funct3 & 0b11);
+                    self.taint_load(inst.rd, addr, size);
+                    let val = match inst.funct3 {
                         0b000 => {
                             // LB
-                            let mut tmp = [0u8; 1];
-                            self.memory.read_into(addr, &mut tmp)?;
-                            self.set_reg(inst.rd, 
-                                         i8::from_le_bytes(tmp) as i64 as u64);
+                            let val = self.memory.read::<i8>(addr)?;
+                            val as i64 as u64
                         },
                          0b001 => {
                             // LH
-                            let mut tmp = [0u8; 2];
-                            self.memory.read_into(addr, &mut tmp)?;
-                            self.set_reg(inst.rd, 
-                                         i16::from_le_bytes(tmp) as i64 as u64);
+                            let val = self.memory.read::<i16>(addr)?;
+                            val as i64 as u64
                         },
                         0b010 => {
                             // LW
-                            let mut tmp = [0u8; 4];
-                            self.memory.read_into(addr, &mut tmp)?;
-                            self.set_reg(inst.rd, 
-                                         i32::from_le_bytes(tmp) as i64 as u64);
+                            let val = self.memory.read::<i32>(addr)?;
+                            val as i64 as u64
                         },
                         0b011 => {
                             // LD
-                            let mut tmp = [0u8; 8];
-                            self.memory.read_into(addr, &mut tmp)?;
-                            self.set_reg(inst.rd, 
-                                         i64::from_le_bytes(tmp) as i64 as u64);
+                            let val = self.memory.read::<i64>(addr)?;
+                            val as u64
                         },
                         0b100 => {
                             // LBU
-                            let mut tmp = [0u8; 1];
-                            self.memory.read_into(addr, &mut tmp)?;
-                            self.set_reg(inst.rd, 
-                                         u8::from_le_bytes(tmp)  as u64);
+                            let val = self.memory.read::<u8>(addr)?;
+                            val as u64
                         },
                         0b101 => {
                             // LHU
-                            let mut tmp = [0u8; 2];
-                            self.memory.read_into(addr, &mut tmp)?;
-                            self.set_reg(inst.rd, 
-                                         u16::from_le_bytes(tmp)  as u64);
+                            let val = self.memory.read::<u16>(addr)?;
+                            val as u64
                         },
                          0b110 => {
                             // LWU
-                            let mut tmp = [0u8; 4];
-                            self.memory.read_into(addr, &mut tmp)?;
-                            self.set_reg(inst.rd, 
-                                         u32::from_le_bytes(tmp)  as u64);
+                            let val = self.memory.read::<u32>(addr)?;
+                            val as u64
                         },
 
                           _ => unimplemented!("Unexpected 0b0000011\n"),
 
-                    }
+                    };
+                    self.set_reg(inst.rd, val);
+                    self.trace(pc, addr, size, val, PERM_READ);
                   },
                   0b0100011 =>  {
                       // We know it's a Stype
@@ -546,7 +982,7 @@ impl Emulator {
                           },
                           0b011 =>{
                               // SD
-                              let val = self.reg(inst.rs2) as u64;
+                              let val = self.reg(inst.rs2);
                               self.memory.write(addr, val)?;
 
                           },
@@ -555,6 +991,12 @@ impl Emulator {
 
                       }
 
+                      // Label the stored bytes and check the address
+                      self.taint_store(inst.rs2, addr, 1 << inst.funct3);
+                      self.taint_record(pc, TaintUse::StoreAddress, &[inst.rs1]);
+                      self.trace(pc, addr, 1 << inst.funct3, self.reg(inst.rs2),
+                                 PERM_WRITE);
+
                   },
                   0b0010011 => {
                       // We know it's a Itype
@@ -562,6 +1004,7 @@ impl Emulator {
 
                       let rs1 = self.reg(inst.rs1);
                       let imm = inst.imm as i64 as u64;
+                      self.taint_flow(inst.rd, &[inst.rs1]);
 
                       match inst.funct3 {
                           0b000 => {
@@ -578,7 +1021,7 @@ impl Emulator {
                           },
                           0b011 => {
                               // SLTIU
-                              if (rs1 as u64) < (imm  as u64){
+                              if rs1 < imm{
                                   self.set_reg(inst.rd, 1);
                               } else {
                                   self.set_reg(inst.rd, 0);
@@ -637,6 +1080,7 @@ impl Emulator {
 
                       let rs1 = self.reg(inst.rs1) as u32;
                       let rs2 = self.reg(inst.rs2) as u32;
+                      self.taint_flow(inst.rd, &[inst.rs1, inst.rs2]);
 
                       match (inst.funct7, inst.funct3) {
                           (0b000000, 0b000) => {
@@ -676,6 +1120,7 @@ impl Emulator {
 
                       let rs1 = self.reg(inst.rs1);
                       let rs2 = self.reg(inst.rs2);
+                      self.taint_flow(inst.rd, &[inst.rs1, inst.rs2]);
 
                       match (inst.funct7, inst.funct3) {
                           (0b000000, 0b000) => {
@@ -701,7 +1146,7 @@ impl Emulator {
                           },
                           (0b000000, 0b011) => {
                               // SLTU
-                              if (rs1 as u64) < (rs2 as u64){
+                              if rs1 < rs2{
                                   self.set_reg(inst.rd, 1);
                               } else {
                                   self.set_reg(inst.rd, 0);
@@ -749,7 +1194,8 @@ impl Emulator {
 
                   0b1110011 => {
                       if inst == 0b00000000000000000000000001110011 {
-                          // Ecall
+                          // Ecall, the result comes from the host
+                           self.taint_flow(Register::A0, &[]);
                            return Err(VmExit::Syscall);
                       } else if inst == 0b00000000000100000000000001110011 {
                           // EBRAKE
@@ -763,6 +1209,7 @@ impl Emulator {
 
                       let rs1 = self.reg(inst.rs1) as u32;
                       let imm = inst.imm as u32;
+                      self.taint_flow(inst.rd, &[inst.rs1]);
 
                       match inst.funct3 {
                           0b000 => {
@@ -796,7 +1243,7 @@ impl Emulator {
                                       // SRAIW
                                       let shamt = inst.imm & 0b11111;
                                       self.set_reg(inst.rd, 
-                                                   ((rs1 as i64) >> shamt) as i64 as u64);
+                                                   ((rs1 as i64) >> shamt) as u64);
                                   }
                                   _ => unreachable!(),
                              }
@@ -822,5 +1269,481 @@ impl Emulator {
     // run end
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscall::{self, Syscalls};
+    use crate::taint::TaintEvent;
+    use crate::vfs;
+
+    /// Where test guests are loaded
+    const CODE: usize = 0x1000;
+
+    /// A page of readable and writable memory for test guests
+    const DATA: usize = 0x2000;
+
+    /// Create an emulator about to run the instructions `code`
+    fn guest(code: &[u32]) -> Emulator {
+        let mut emu = Emulator::new(0x4000);
+        let bytes: Vec<u8> = code.iter().flat_map(|x| x.to_le_bytes()).collect();
+
+        emu.memory.set_permissions(VirtAddr(CODE), bytes.len(), Perm(PERM_WRITE))
+            .unwrap();
+        emu.memory.write_from(VirtAddr(CODE), &bytes).unwrap();
+        emu.memory.set_permissions(VirtAddr(CODE), bytes.len(),
+                                   Perm(PERM_READ | PERM_EXEC)).unwrap();
+        emu.memory.set_permissions(VirtAddr(DATA), 0x1000,
+                                   Perm(PERM_READ | PERM_WRITE)).unwrap();
+        emu.set_reg(Register::Pc, CODE as u64);
+        emu
+    }
+
+    /// Finish the system call `emu` stopped at the way the host loop does
+    fn skip_ecall(emu: &mut Emulator) {
+        let pc = emu.reg(Register::Pc);
+        emu.set_reg(Register::Pc, pc + 4);
+    }
+
+    /// A static ELF with one executable segment at 0x10000, naming `interp`
+    /// as its interpreter if given
+    fn elf(interp: Option<&str>) -> Vec<u8> {
+        let phnum: u16 = if interp.is_some() { 2 } else { 1 };
+        let mut image = vec![0u8; 0x200];
+        image[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        image[0x10..0x12].copy_from_slice(&2u16.to_le_bytes());
+        image[0x12..0x14].copy_from_slice(&243u16.to_le_bytes());
+        image[0x18..0x20].copy_from_slice(&0x10000u64.to_le_bytes());
+        image[0x20..0x28].copy_from_slice(&0x40u64.to_le_bytes());
+        image[0x36..0x38].copy_from_slice(&0x38u16.to_le_bytes());
+        image[0x38..0x3a].copy_from_slice(&phnum.to_le_bytes());
+
+        let mut phdr = |off: usize, p_type: u32, p_flags: u32, p_offset: u64,
+                        p_vaddr: u64, p_size: u64| {
+            image[off..off + 4].copy_from_slice(&p_type.to_le_bytes());
+            image[off + 4..off + 8].copy_from_slice(&p_flags.to_le_bytes());
+            image[off + 0x08..off + 0x10].copy_from_slice(&p_offset.to_le_bytes());
+            image[off + 0x10..off + 0x18].copy_from_slice(&p_vaddr.to_le_bytes());
+            image[off + 0x20..off + 0x28].copy_from_slice(&p_size.to_le_bytes());
+            image[off + 0x28..off + 0x30].copy_from_slice(&p_size.to_le_bytes());
+        };
+        phdr(0x40, 1, 5, 0, 0x10000, 0x200);
+        if let Some(path) = interp {
+            phdr(0x78, 3, 4, 0x100, 0, path.len() as u64 + 1);
+            image[0x100..0x100 + path.len()].copy_from_slice(path.as_bytes());
+        }
+        image
+    }
+
+    #[test]
+    fn dynamically_linked_guests_are_refused() {
+        let mut emu = Emulator::new(0x20000);
+        let err = emu.load_elf(&elf(Some("/lib/ld-linux-riscv64-lp64.so.1")))
+            .err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(err.to_string().contains("dynamic linking is unsupported"));
+        assert!(err.to_string().contains("ld-linux-riscv64-lp64.so.1"));
+
+        let mut emu = Emulator::new(0x20000);
+        emu.load_elf(&elf(None)).unwrap();
+        assert_eq!(emu.reg(Register::Pc), 0x10000);
+    }
 
+    #[test]
+    fn watchpoint_hits_of_system_calls_are_reported() {
+        let mut emu = guest(&[
+            0x00000073, // ecall
+            0x00100513, // li a0, 1
+            0x00000073, // ecall
+        ]);
+        emu.memory.add_watchpoint(VirtAddr(DATA), 8, Perm(PERM_WRITE)).unwrap();
+
+        // A `read` filling the watched buffer
+        assert_eq!(emu.run(), Err(VmExit::Syscall));
+        emu.memory.write_from(VirtAddr(DATA), b"flag").unwrap();
+        skip_ecall(&mut emu);
+
+        assert_eq!(emu.run(), Err(VmExit::Watchpoint {
+            addr: VirtAddr(DATA), kind: Perm(PERM_WRITE), pc: VirtAddr(CODE),
+        }));
+        assert_eq!(emu.run(), Err(VmExit::Syscall));
+        assert_eq!(emu.reg(Register::Pc), CODE as u64 + 8);
+        assert_eq!(emu.reg(Register::A0), 1);
+    }
 
+    #[test]
+    fn exec_watchpoints_on_ecall_are_reported() {
+        let mut emu = guest(&[
+            0x00000073, // ecall
+            0x00000073, // ecall
+        ]);
+        emu.memory.add_watchpoint(VirtAddr(CODE), 4, Perm(PERM_EXEC)).unwrap();
+
+        assert_eq!(emu.run(), Err(VmExit::Syscall));
+        skip_ecall(&mut emu);
+        assert_eq!(emu.run(), Err(VmExit::Watchpoint {
+            addr: VirtAddr(CODE), kind: Perm(PERM_EXEC), pc: VirtAddr(CODE),
+        }));
+        assert_eq!(emu.run(), Err(VmExit::Syscall));
+        assert_eq!(emu.reg(Register::Pc), CODE as u64 + 4);
+    }
+
+    #[test]
+    fn trace_filters_only_pass_accesses_in_range() {
+        let mut emu = guest(&[
+            0x00943023, // sd s1, 0(s0)
+            0x10943023, // sd s1, 256(s0)
+            0x10442283, // lw t0, 260(s0)
+            0x0ff40303, // lb t1, 255(s0)
+            0x109417a3, // sh s1, 271(s0)
+            0x11040383, // lb t2, 272(s0)
+            0x00000073, // ecall
+        ]);
+        emu.set_reg(Register::S0, DATA as u64);
+        emu.set_reg(Register::S1, 0x1122334455667788);
+
+        let sink = Arc::new(Mutex::new(Vec::new()));
+        emu.set_trace_sink(sink.clone());
+        emu.add_trace_filter(VirtAddr(DATA + 0x100), 0x10).unwrap();
+        assert_eq!(emu.run(), Err(VmExit::Syscall));
+
+        // Accesses just outside the range are left out, ones straddling its
+        // end are kept
+        let access = |pc, addr, size, value, kind| MemAccess {
+            pc: VirtAddr(CODE + pc), addr: VirtAddr(DATA + addr), size, value,
+            kind: Perm(kind),
+        };
+        assert_eq!(*sink.lock().unwrap(), [
+            access(4,  0x100, 8, 0x1122334455667788, PERM_WRITE),
+            access(8,  0x104, 4, 0x11223344,         PERM_READ),
+            access(16, 0x10f, 2, 0x7788,             PERM_WRITE),
+        ]);
+    }
+
+    /// A test ELF whose symbol table holds the function `victim` covering
+    /// the test code
+    fn elf_with_symbols() -> Vec<u8> {
+        let mut image = elf(None);
+        image.resize(0x400, 0);
+        image[0x200..0x208].copy_from_slice(b"\0victim\0");
+        image[0x210..0x22b].copy_from_slice(b"\0.symtab\0.strtab\0.shstrtab\0");
+
+        // The null symbol, then `victim`
+        image[0x258..0x25c].copy_from_slice(&1u32.to_le_bytes());
+        image[0x25c] = 2;
+        image[0x25e..0x260].copy_from_slice(&1u16.to_le_bytes());
+        image[0x260..0x268].copy_from_slice(&(CODE as u64).to_le_bytes());
+        image[0x268..0x270].copy_from_slice(&0x100u64.to_le_bytes());
+
+        // Section headers after a null one: .symtab, .strtab and .shstrtab
+        let sections = [(1u32, 2u32, 0x240u64, 0x30u64), (9, 3, 0x200, 8),
+                        (17, 3, 0x210, 0x1b)];
+        for (idx, &(name, kind, off, size)) in sections.iter().enumerate() {
+            let hdr = 0x2c0 + idx * 0x40;
+            image[hdr..hdr + 4].copy_from_slice(&name.to_le_bytes());
+            image[hdr + 4..hdr + 8].copy_from_slice(&kind.to_le_bytes());
+            image[hdr + 0x18..hdr + 0x20].copy_from_slice(&off.to_le_bytes());
+            image[hdr + 0x20..hdr + 0x28].copy_from_slice(&size.to_le_bytes());
+        }
+        image[0x28..0x30].copy_from_slice(&0x280u64.to_le_bytes());
+        image[0x3a..0x3c].copy_from_slice(&0x40u16.to_le_bytes());
+        image[0x3c..0x3e].copy_from_slice(&4u16.to_le_bytes());
+        image[0x3e..0x40].copy_from_slice(&3u16.to_le_bytes());
+        image
+    }
+
+    /// Output shared with the test after it's handed to a trace log
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn trace_logs_name_the_code() {
+        let code = [
+            0x00943023, // sd s1, 0(s0)
+            0x00442283, // lw t0, 4(s0)
+            0x00000073, // ecall
+        ];
+
+        let mut emu = guest(&code);
+        emu.set_symbols(Arc::new(Symbols::parse(&elf_with_symbols(), 0)));
+        assert_eq!(emu.symbols().unwrap().address("victim"), Some(VirtAddr(CODE)));
+        emu.set_reg(Register::S0, DATA as u64);
+        emu.set_reg(Register::S1, 0x1122334455667788);
+        let out = SharedOutput::default();
+        emu.set_trace_log(out.clone());
+        assert_eq!(emu.run(), Err(VmExit::Syscall));
+        assert_eq!(String::from_utf8(out.0.lock().unwrap().clone()).unwrap(),
+                   "write 0x2000 8 bytes 0x1122334455667788 at pc 0x1000 <victim>\n\
+                    read 0x2004 4 bytes 0x11223344 at pc 0x1004 <victim+0x4>\n");
+
+        // Without symbols only the PC is known
+        let mut emu = guest(&code);
+        emu.set_reg(Register::S0, DATA as u64);
+        let out = SharedOutput::default();
+        emu.set_trace_log(out.clone());
+        assert_eq!(emu.run(), Err(VmExit::Syscall));
+        assert_eq!(String::from_utf8(out.0.lock().unwrap().clone()).unwrap(),
+                   "write 0x2000 8 bytes 0x0 at pc 0x1000\n\
+                    read 0x2004 4 bytes 0x0 at pc 0x1004\n");
+    }
+
+    /// Read the byte at `addr` of a test guest
+    fn byte(emu: &Emulator, addr: usize) -> u8 {
+        let mut buf = [0u8];
+        emu.memory.read_into(VirtAddr(addr), &mut buf).unwrap();
+        buf[0]
+    }
+
+    #[test]
+    fn checkpoints_restore_newest_first() {
+        let mut emu = guest(&[]);
+        emu.memory.write_from(VirtAddr(DATA), b"a").unwrap();
+        emu.set_reg(Register::A0, 1);
+
+        assert_eq!(emu.checkpoint(), 0);
+        emu.memory.write_from(VirtAddr(DATA), b"b").unwrap();
+        emu.memory.set_permissions(VirtAddr(DATA + 0x100), 1, Perm(0)).unwrap();
+        emu.set_reg(Register::A0, 2);
+
+        assert_eq!(emu.checkpoint(), 1);
+        emu.memory.write_from(VirtAddr(DATA), b"c").unwrap();
+        emu.vfs.add_file("new", Vec::new());
+        emu.set_reg(Register::A0, 3);
+
+        // The restored checkpoint stays on the stack and can be used again
+        emu.restore(1).unwrap();
+        assert_eq!((byte(&emu, DATA), emu.reg(Register::A0)), (b'b', 2));
+        assert_eq!(emu.vfs.file("new"), None);
+        emu.memory.write_from(VirtAddr(DATA), b"d").unwrap();
+        emu.restore(1).unwrap();
+        assert_eq!(byte(&emu, DATA), b'b');
+        assert_eq!(emu.checkpoint_depth(), 2);
+        assert_eq!(emu.memory.checkpoint_depth(), 2);
+
+        emu.restore(0).unwrap();
+        assert_eq!((byte(&emu, DATA), emu.reg(Register::A0)), (b'a', 1));
+        assert_eq!(emu.memory.perms(VirtAddr(DATA + 0x100)),
+                   Some(Perm(PERM_READ | PERM_WRITE)));
+
+        // Restoring the outer checkpoint dropped the inner one
+        assert_eq!(emu.restore(1), None);
+        assert_eq!(emu.checkpoint_depth(), 1);
+        assert_eq!(emu.memory.checkpoint_depth(), 1);
+        assert_eq!(byte(&emu, DATA), b'a');
+    }
+
+    #[test]
+    fn inner_checkpoints_keep_outer_writes() {
+        let mut emu = guest(&[]);
+        emu.checkpoint();
+        emu.memory.write_from(VirtAddr(DATA), b"outer").unwrap();
+        emu.memory.write_from(VirtAddr(DATA + 0x800), b"far").unwrap();
+
+        // The first write after the inner checkpoint is to a block the outer
+        // one saved already
+        emu.checkpoint();
+        emu.memory.write_from(VirtAddr(DATA + 8), b"inner").unwrap();
+
+        emu.restore(1).unwrap();
+        let mut buf = [0u8; 13];
+        emu.memory.read_into(VirtAddr(DATA), &mut buf).unwrap();
+        assert_eq!(&buf, b"outer\0\0\0\0\0\0\0\0");
+        assert_eq!(byte(&emu, DATA + 0x800), b'f');
+
+        emu.restore(0).unwrap();
+        emu.memory.read_into(VirtAddr(DATA), &mut buf).unwrap();
+        assert_eq!(buf, [0u8; 13]);
+        assert_eq!(byte(&emu, DATA + 0x800), 0);
+    }
+
+    #[test]
+    fn checkpoints_are_dropped_by_reset() {
+        let parent = guest(&[]);
+        let mut emu = parent.fork();
+        emu.checkpoint();
+        emu.memory.write_from(VirtAddr(DATA), b"x").unwrap();
+
+        emu.reset(&parent).unwrap();
+        assert_eq!(emu.checkpoint_depth(), 0);
+        assert_eq!(emu.memory.checkpoint_depth(), 0);
+        assert_eq!(emu.restore(0), None);
+        assert_eq!(byte(&emu, DATA), 0);
+
+        // New checkpoints work as before
+        assert_eq!(emu.checkpoint(), 0);
+        emu.memory.write_from(VirtAddr(DATA), b"y").unwrap();
+        emu.restore(0).unwrap();
+        assert_eq!(byte(&emu, DATA), 0);
+    }
+
+    /// A tainted guest: a load of the 8 input bytes at `DATA`, an ALU op on
+    /// them, a store of the result and a branch on it
+    fn taint_guest() -> Emulator {
+        let mut emu = guest(&[
+            0x00043283, // ld t0, 0(s0)
+            0x00128313, // addi t1, t0, 1
+            0x00643423, // sd t1, 8(s0)
+            0x009303b3, // add t2, t1, s1
+            0x00038063, // beqz t2, 0
+            0x00000073, // ecall
+        ]);
+        emu.enable_taint();
+        emu.set_reg(Register::S0, DATA as u64);
+        emu
+    }
+
+    /// Check that `size` bytes at `addr` are clean
+    fn assert_clean(emu: &Emulator, addr: usize, size: usize) {
+        let labels = emu.memory.taint(VirtAddr(addr), size).unwrap();
+        assert!(labels.iter().all(|x| !x.is_tainted()));
+    }
+
+    #[test]
+    fn taint_flows_from_loads_through_alu_ops_to_stores() {
+        let mut emu = taint_guest();
+        emu.memory.write_from(VirtAddr(DATA), b"input!!!").unwrap();
+        emu.taint_input(VirtAddr(DATA), 8);
+        assert_eq!(emu.run(), Err(VmExit::Syscall));
+
+        let taint = emu.taint().unwrap();
+        let label = taint.reg(Register::T1);
+        assert_eq!(taint.sources(label), (0..8).collect::<Vec<_>>());
+        assert_eq!(taint.reg(Register::T0), label);
+        assert_eq!(taint.reg(Register::T2), label);
+        assert!(emu.memory.taint(VirtAddr(DATA + 8), 8).unwrap()
+            .iter().all(|&x| x == label));
+        assert_eq!(taint.events(), [TaintEvent {
+            pc: VirtAddr(CODE + 16), kind: TaintUse::Branch, label,
+        }]);
+
+        // Clean data stays clean
+        assert!(!taint.reg(Register::S0).is_tainted());
+        assert!(!taint.reg(Register::S1).is_tainted());
+        assert_clean(&emu, DATA + 16, 0x100);
+    }
+
+    #[test]
+    fn taint_is_undone_by_reset() {
+        let parent = taint_guest();
+        let mut emu = parent.fork();
+        emu.memory.write_from(VirtAddr(DATA), b"input!!!").unwrap();
+        emu.taint_input(VirtAddr(DATA), 8);
+        assert_eq!(emu.run(), Err(VmExit::Syscall));
+
+        // Forks of the parent don't see the taint of their siblings
+        let sibling = parent.fork();
+        assert!(!sibling.taint().unwrap().reg(Register::T1).is_tainted());
+        assert_clean(&sibling, DATA, 16);
+
+        emu.reset(&parent).unwrap();
+        let taint = emu.taint().unwrap();
+        for reg in [Register::T0, Register::T1, Register::T2] {
+            assert!(!taint.reg(reg).is_tainted());
+        }
+        assert!(taint.events().is_empty());
+        assert_clean(&emu, DATA, 16);
+
+        // Labels are handed out afresh, and labelling memory without writing
+        // it is undone too
+        emu.taint_input(VirtAddr(DATA + 0x800), 4);
+        assert_eq!(emu.memory.taint(VirtAddr(DATA + 0x800), 1).unwrap(),
+                   [TaintLabel(1)]);
+        emu.reset(&parent).unwrap();
+        assert_clean(&emu, DATA + 0x800, 4);
+    }
+
+    /// Issue `read(fd, buf, count)`, returning the result in A0
+    fn read(emu: &mut Emulator, fd: u64, buf: usize, count: u64) -> u64 {
+        emu.set_reg(Register::A7, syscall::SYS_READ);
+        emu.set_reg(Register::A0, fd);
+        emu.set_reg(Register::A1, buf as u64);
+        emu.set_reg(Register::A2, count);
+        Syscalls::linux().dispatch(emu).unwrap();
+        emu.reg(Register::A0)
+    }
+
+    #[test]
+    fn bad_descriptors_are_reported_before_bad_buffers() {
+        let mut emu = guest(&[]);
+        emu.vfs.add_file("flag", b"TMCTF{}".to_vec());
+        let flag = emu.vfs.open(b"flag", vfs::O_RDONLY).unwrap();
+        let out = emu.vfs.open(b"out", vfs::O_CREAT | vfs::O_WRONLY).unwrap();
+
+        let mut write = |fd: u64, buf: usize| {
+            emu.set_reg(Register::A7, syscall::SYS_WRITE);
+            emu.set_reg(Register::A0, fd);
+            emu.set_reg(Register::A1, buf as u64);
+            emu.set_reg(Register::A2, 4);
+            Syscalls::linux().dispatch(&mut emu).unwrap();
+            emu.reg(Register::A0).wrapping_neg()
+        };
+
+        // Closed, read-only and stdin descriptors with a buffer which isn't
+        // mapped at all
+        for fd in [99, u64::MAX, flag, 0] {
+            assert_eq!(write(fd, 0x10_0000), syscall::EBADF, "fd {}", fd);
+        }
+        assert_eq!(write(out, 0x10_0000), syscall::EFAULT);
+        assert_eq!(write(out, 0x3ffe), syscall::EFAULT);
+        assert_eq!(write(out, DATA), 4u64.wrapping_neg());
+    }
+
+    #[test]
+    fn faulting_reads_leave_the_input_unread() {
+        let efault = syscall::EFAULT.wrapping_neg();
+        let mut buf = [0u8; 6];
+
+        for line_mode in [false, true] {
+            let mut emu = guest(&[]);
+            let mut input = Input::bytes(b"hello\nworld\n".to_vec());
+            input.set_line_mode(line_mode);
+            emu.stdin = Arc::new(Mutex::new(input));
+
+            assert_eq!(read(&mut emu, 0, 0x3000, 6), efault);
+            assert_eq!(read(&mut emu, 0, DATA, 6), 6);
+            emu.memory.read_into(VirtAddr(DATA), &mut buf).unwrap();
+            assert_eq!(&buf, if line_mode { b"hello\0" } else { b"hello\n" });
+        }
+
+        // Files are rewound
+        let mut emu = guest(&[]);
+        emu.vfs.add_file("flag", b"TMCTF{}".to_vec());
+        let fd = emu.vfs.open(b"flag", vfs::O_RDONLY).unwrap();
+        assert_eq!(read(&mut emu, fd, DATA + 0xffc, 6), efault);
+        assert_eq!(read(&mut emu, fd, DATA, 6), 6);
+        emu.memory.read_into(VirtAddr(DATA), &mut buf).unwrap();
+        assert_eq!(&buf, b"TMCTF{");
+    }
+
+    #[test]
+    fn snapshots_keep_open_files() {
+        let mut emu = guest(&[]);
+        emu.vfs.add_file("flag", b"TMCTF{snapshot}".to_vec());
+        let fd = emu.vfs.open(b"flag", vfs::O_RDONLY).unwrap();
+        assert_eq!(fd, 3);
+        emu.vfs.read(fd, 6).unwrap();
+
+        let path = std::env::temp_dir()
+            .join(format!("rustme-snapshot-{}", std::process::id()));
+        emu.save_snapshot(&path).unwrap();
+        let loaded = Emulator::load_snapshot(&path);
+        std::fs::remove_file(&path).unwrap();
+        let mut emu = loaded.unwrap();
+
+        // The restored guest reads on from the saved offset
+        assert_eq!(read(&mut emu, fd, DATA, 0x100), 9);
+
+        let mut data = [0u8; 9];
+        emu.memory.read_into(VirtAddr(DATA), &mut data).unwrap();
+        assert_eq!(&data, b"snapshot}");
+    }
+}
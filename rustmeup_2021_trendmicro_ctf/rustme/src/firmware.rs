@@ -0,0 +1,407 @@
+//! Loaders for bare-metal firmware: raw flat binaries, Intel HEX and
+//! Motorola S-record images
+//!
+//! Every loader produces a `Firmware`, the image bytes with `Sections`
+//! describing where they go, so firmware is loaded by `Mmu::load_image` just
+//! like an ELF. Records at adjacent addresses are merged into one segment,
+//! and `protect` changes the permissions of any part of the image.
+
+use crate::mmu::{VirtAddr, Perm, Sections};
+use std::io;
+
+/// Formats of firmware images
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    /// Raw bytes loaded at a given base address
+    Flat,
+
+    /// Intel HEX records
+    IntelHex,
+
+    /// Motorola S-records
+    SRecord,
+}
+
+impl Format {
+    /// Get the format called `name`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "flat" | "bin"         => Some(Format::Flat),
+            "ihex" | "hex"         => Some(Format::IntelHex),
+            "srec" | "s19" | "mot" => Some(Format::SRecord),
+            _ => None,
+        }
+    }
+}
+
+/// A firmware image ready to load
+pub struct Firmware {
+    /// Contents of every segment, back to back
+    pub image: Vec<u8>,
+
+    /// Where the parts of `image` are loaded, sorted by address
+    pub segments: Vec<Sections>,
+
+    /// Address execution starts at, the start address given by the image or
+    /// else its lowest address
+    pub entry: VirtAddr,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Decode a string of hex digit pairs
+fn hex_bytes(text: &[u8]) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    text.chunks(2).map(|pair| {
+        let digits = std::str::from_utf8(pair).ok()?;
+        u8::from_str_radix(digits, 16).ok()
+    }).collect()
+}
+
+/// Big endian value of `bytes`
+fn be_value(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |acc, &x| (acc << 8) | x as usize)
+}
+
+/// Non-empty lines of a text image, without surrounding whitespace
+fn records(text: &[u8]) -> impl Iterator<Item = &[u8]> {
+    text.split(|&x| x == b'\n').map(|line| {
+        let start = line.iter().position(|x| !x.is_ascii_whitespace())
+            .unwrap_or(line.len());
+        let end = line.iter().rposition(|x| !x.is_ascii_whitespace())
+            .map_or(start, |x| x + 1);
+        &line[start..end]
+    }).filter(|line| !line.is_empty())
+}
+
+impl Firmware {
+    /// Parse `data` as `format`. `base` is the load address of flat images,
+    /// and every segment gets `perm` until changed with `protect`
+    pub fn parse(format: Format, data: &[u8], base: VirtAddr, perm: Perm)
+            -> io::Result<Self> {
+        match format {
+            Format::Flat     => Firmware::flat(data, base, perm),
+            Format::IntelHex => Firmware::intel_hex(data, perm),
+            Format::SRecord  => Firmware::srecord(data, perm),
+        }
+    }
+
+    /// Load the raw bytes of `data` at `base`, starting execution there
+    pub fn flat(data: &[u8], base: VirtAddr, perm: Perm) -> io::Result<Self> {
+        Firmware::from_chunks(vec![(base.0, data.to_vec())], perm, Some(base))
+    }
+
+    /// Parse the Intel HEX records in `text`
+    pub fn intel_hex(text: &[u8], perm: Perm) -> io::Result<Self> {
+        let mut chunks = Vec::new();
+        let mut entry = None;
+
+        // Upper address bits set by extended segment and linear records
+        let mut upper = 0usize;
+
+        for line in records(text) {
+            let bytes = line.strip_prefix(b":").and_then(hex_bytes)
+                .ok_or_else(|| invalid("malformed Intel HEX record"))?;
+            if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+                return Err(invalid("bad Intel HEX record length"));
+            }
+            if bytes.iter().fold(0u8, |acc, &x| acc.wrapping_add(x)) != 0 {
+                return Err(invalid("bad Intel HEX checksum"));
+            }
+
+            let offset = be_value(&bytes[1..3]);
+            let data = &bytes[4..bytes.len() - 1];
+            match (bytes[3], data.len()) {
+                (0x00, _) => chunks.push((upper + offset, data.to_vec())),
+                (0x01, _) => break,
+                (0x02, 2) => upper = be_value(data) << 4,
+                (0x03, 4) => {
+                    entry = Some(VirtAddr((be_value(&data[..2]) << 4) +
+                                          be_value(&data[2..])));
+                }
+                (0x04, 2) => upper = be_value(data) << 16,
+                (0x05, 4) => entry = Some(VirtAddr(be_value(data))),
+                _ => return Err(invalid("unsupported Intel HEX record")),
+            }
+        }
+
+        Firmware::from_chunks(chunks, perm, entry)
+    }
+
+    /// Parse the Motorola S-records in `text`
+    pub fn srecord(text: &[u8], perm: Perm) -> io::Result<Self> {
+        let mut chunks = Vec::new();
+        let mut entry = None;
+
+        for line in records(text) {
+            let (kind, bytes) = match line {
+                [b'S', kind, rest @ ..] => (*kind, hex_bytes(rest)),
+                _ => (0, None),
+            };
+            let bytes = bytes.ok_or_else(|| invalid("malformed S-record"))?;
+            if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+                return Err(invalid("bad S-record length"));
+            }
+            if bytes.iter().fold(0u8, |acc, &x| acc.wrapping_add(x)) != 0xff {
+                return Err(invalid("bad S-record checksum"));
+            }
+
+            // Size of the address field, data follows it
+            let addr_size = match kind {
+                b'0' | b'1' | b'5' | b'9' => 2,
+                b'2' | b'6' | b'8'        => 3,
+                b'3' | b'7'               => 4,
+                _ => return Err(invalid("unsupported S-record")),
+            };
+            let payload = &bytes[1..bytes.len() - 1];
+            if payload.len() < addr_size {
+                return Err(invalid("bad S-record length"));
+            }
+            let addr = be_value(&payload[..addr_size]);
+
+            match kind {
+                b'1' | b'2' | b'3' => {
+                    chunks.push((addr, payload[addr_size..].to_vec()));
+                }
+                b'7' | b'8' | b'9' => entry = Some(VirtAddr(addr)),
+
+                // Headers and record counts
+                _ => {}
+            }
+        }
+
+        Firmware::from_chunks(chunks, perm, entry)
+    }
+
+    /// Build the image from `(address, bytes)` chunks, merging adjacent ones
+    fn from_chunks(mut chunks: Vec<(usize, Vec<u8>)>, perm: Perm,
+                   entry: Option<VirtAddr>) -> io::Result<Self> {
+        chunks.retain(|(_, data)| !data.is_empty());
+        chunks.sort_by_key(|&(addr, _)| addr);
+
+        let mut image = Vec::new();
+        let mut segments: Vec<Sections> = Vec::new();
+        for (addr, data) in chunks {
+            addr.checked_add(data.len())
+                .ok_or_else(|| invalid("record past the end of memory"))?;
+
+            match segments.last_mut() {
+                Some(last) if last.virt_addr.0 + last.mem_size > addr => {
+                    return Err(invalid("overlapping records"));
+                }
+                Some(last) if last.virt_addr.0 + last.mem_size == addr => {
+                    last.file_size += data.len();
+                    last.mem_size  += data.len();
+                }
+                _ => segments.push(Sections {
+                    file_offset: image.len(),
+                    virt_addr:   VirtAddr(addr),
+                    file_size:   data.len(),
+                    mem_size:    data.len(),
+                    permissions: perm,
+                }),
+            }
+            image.extend_from_slice(&data);
+        }
+
+        let entry = entry.or_else(|| segments.first().map(|x| x.virt_addr))
+            .ok_or_else(|| invalid("empty firmware image"))?;
+
+        Ok(Firmware { image, segments, entry })
+    }
+
+    /// Give the `size` bytes of the image at `addr` the permissions `perm`,
+    /// splitting segments as needed. Returns `None` if the range covers no
+    /// part of the image
+    pub fn protect(&mut self, addr: VirtAddr, size: usize, perm: Perm)
+            -> Option<()> {
+        let end = addr.0.checked_add(size)?;
+        let covers = |segment: &Sections| {
+            let seg_end = segment.virt_addr.0 + segment.mem_size;
+            addr.0 < seg_end && segment.virt_addr.0 < end && addr.0 < end
+        };
+        if !self.segments.iter().any(covers) {
+            return None;
+        }
+
+        let mut segments = Vec::new();
+        for segment in self.segments.drain(..) {
+            let start = segment.virt_addr.0;
+            let seg_end = start + segment.mem_size;
+            let lo = addr.0.clamp(start, seg_end);
+            let hi = end.clamp(start, seg_end);
+
+            let parts = [
+                (start, lo, segment.permissions),
+                (lo, hi, perm),
+                (hi, seg_end, segment.permissions),
+            ];
+            for &(part_start, part_end, permissions) in &parts {
+                if part_start < part_end {
+                    segments.push(Sections {
+                        file_offset: segment.file_offset + (part_start - start),
+                        virt_addr:   VirtAddr(part_start),
+                        file_size:   part_end - part_start,
+                        mem_size:    part_end - part_start,
+                        permissions,
+                    });
+                }
+            }
+        }
+        self.segments = segments;
+
+        Some(())
+    }
+
+    /// Address just past the highest segment
+    pub fn end(&self) -> usize {
+        self.segments.last().map_or(0, |x| x.virt_addr.0 + x.mem_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmu::{PERM_READ, PERM_WRITE, PERM_EXEC};
+
+    const RWX: Perm = Perm(PERM_READ | PERM_WRITE | PERM_EXEC);
+
+    /// Intel HEX record of `kind` with its checksum
+    fn ihex(kind: u8, offset: u16, data: &[u8]) -> String {
+        let mut bytes = vec![data.len() as u8, (offset >> 8) as u8, offset as u8, kind];
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |acc, &x| acc.wrapping_add(x));
+        bytes.push(sum.wrapping_neg());
+        format!(":{}\n", bytes.iter().map(|x| format!("{:02X}", x)).collect::<String>())
+    }
+
+    /// S-record of `kind` with its checksum
+    fn srec(kind: char, addr: &[u8], data: &[u8]) -> String {
+        let mut bytes = vec![(addr.len() + data.len() + 1) as u8];
+        bytes.extend_from_slice(addr);
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |acc, &x| acc.wrapping_add(x));
+        bytes.push(!sum);
+        format!("S{}{}\n", kind, bytes.iter().map(|x| format!("{:02X}", x)).collect::<String>())
+    }
+
+    /// `(address, size, file offset, permissions)` of every segment
+    fn layout(firmware: &Firmware) -> Vec<(usize, usize, usize, u8)> {
+        firmware.segments.iter().map(|x| {
+            assert_eq!(x.file_size, x.mem_size);
+            (x.virt_addr.0, x.mem_size, x.file_offset, x.permissions.0)
+        }).collect()
+    }
+
+    fn error(format: Format, text: &str) -> String {
+        Firmware::parse(format, text.as_bytes(), VirtAddr(0), RWX)
+            .err().expect("the image should be rejected").to_string()
+    }
+
+    #[test]
+    fn intel_hex_images_load() {
+        let text = [
+            ":0B0010006164647265737320676170A7\n".to_string(),
+            ihex(0x00, 0x001b, b"!"),
+            ihex(0x00, 0x0100, b"\x13\x00\x00\x00"),
+            "  :00000001FF  \n\n".to_string(),
+            ihex(0x00, 0x0200, b"after the end"),
+        ].concat();
+        let firmware = Firmware::parse(Format::IntelHex, text.as_bytes(),
+                                       VirtAddr(0), RWX).unwrap();
+
+        assert_eq!(firmware.image, b"address gap!\x13\x00\x00\x00");
+        assert_eq!(layout(&firmware), [(0x10, 12, 0, RWX.0), (0x100, 4, 12, RWX.0)]);
+        assert_eq!(firmware.entry, VirtAddr(0x10));
+        assert_eq!(firmware.end(), 0x104);
+    }
+
+    #[test]
+    fn srecord_images_load() {
+        let text = [
+            srec('0', &[0, 0], b"hello"),
+            srec('1', &[0x20, 0x00], b"\x13\x00\x00\x00"),
+            srec('1', &[0x10, 0x00], b"\x6f\x00\x00\x00"),
+            srec('5', &[0x00, 0x02], b""),
+            srec('9', &[0x20, 0x00], b""),
+        ].concat();
+        let firmware = Firmware::parse(Format::SRecord, text.as_bytes(),
+                                       VirtAddr(0), RWX).unwrap();
+
+        assert_eq!(firmware.image, b"\x6f\x00\x00\x00\x13\x00\x00\x00");
+        assert_eq!(layout(&firmware), [(0x1000, 4, 0, RWX.0), (0x2000, 4, 4, RWX.0)]);
+        assert_eq!(firmware.entry, VirtAddr(0x2000));
+    }
+
+    #[test]
+    fn extended_addresses_move_records() {
+        let text = [
+            ihex(0x02, 0, &[0x10, 0x00]),
+            ihex(0x00, 0x0004, b"seg"),
+            ihex(0x04, 0, &[0x80, 0x00]),
+            ihex(0x00, 0x0010, b"linear"),
+            ihex(0x03, 0, &[0x10, 0x00, 0x00, 0x04]),
+        ].concat();
+        let firmware = Firmware::parse(Format::IntelHex, text.as_bytes(),
+                                       VirtAddr(0), RWX).unwrap();
+        assert_eq!(layout(&firmware), [(0x1_0004, 3, 0, RWX.0),
+                                       (0x8000_0010, 6, 3, RWX.0)]);
+        assert_eq!(firmware.entry, VirtAddr(0x1_0004));
+
+        let text = ihex(0x05, 0, &[0x80, 0x00, 0x00, 0x10]) +
+            &ihex(0x04, 0, &[0x80, 0x00]) + &ihex(0x00, 0x0010, b"x");
+        let firmware = Firmware::parse(Format::IntelHex, text.as_bytes(),
+                                       VirtAddr(0), RWX).unwrap();
+        assert_eq!(firmware.entry, VirtAddr(0x8000_0010));
+
+        let text = [
+            srec('2', &[0x12, 0x34, 0x56], b"s2"),
+            srec('3', &[0x80, 0x00, 0x00, 0x00], b"s3"),
+            srec('7', &[0x80, 0x00, 0x00, 0x00], b""),
+        ].concat();
+        let firmware = Firmware::parse(Format::SRecord, text.as_bytes(),
+                                       VirtAddr(0), RWX).unwrap();
+        assert_eq!(firmware.image, b"s2s3");
+        assert_eq!(layout(&firmware), [(0x12_3456, 2, 0, RWX.0),
+                                       (0x8000_0000, 2, 2, RWX.0)]);
+        assert_eq!(firmware.entry, VirtAddr(0x8000_0000));
+    }
+
+    #[test]
+    fn bad_checksums_are_rejected() {
+        assert_eq!(error(Format::IntelHex, ":0B0010006164647265737320676170A8"),
+                   "bad Intel HEX checksum");
+
+        let mut record = srec('1', &[0x10, 0x00], b"data");
+        record.replace_range(4..6, "FF");
+        assert_eq!(error(Format::SRecord, &record), "bad S-record checksum");
+    }
+
+    #[test]
+    fn overlapping_records_are_rejected() {
+        let text = ihex(0x00, 0x10, b"abcd") + &ihex(0x00, 0x12, b"ef");
+        assert_eq!(error(Format::IntelHex, &text), "overlapping records");
+
+        let text = srec('1', &[0x10, 0x00], b"abcd") + &srec('1', &[0x10, 0x00], b"e");
+        assert_eq!(error(Format::SRecord, &text), "overlapping records");
+    }
+
+    #[test]
+    fn protect_splits_segments() {
+        let data = vec![0u8; 0x1000];
+        let mut firmware = Firmware::flat(&data, VirtAddr(0x1000), RWX).unwrap();
+
+        assert_eq!(firmware.protect(VirtAddr(0x3000), 0x100, Perm(PERM_READ)), None);
+        assert_eq!(firmware.protect(VirtAddr(0x1400), 0x200, Perm(PERM_READ)), Some(()));
+        assert_eq!(layout(&firmware), [
+            (0x1000, 0x400, 0x000, RWX.0),
+            (0x1400, 0x200, 0x400, PERM_READ),
+            (0x1600, 0xa00, 0x600, RWX.0),
+        ]);
+        assert_eq!(firmware.entry, VirtAddr(0x1000));
+    }
+}
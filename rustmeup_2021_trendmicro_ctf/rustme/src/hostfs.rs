@@ -0,0 +1,111 @@
+//! Sandboxed access to a host directory as the guest's root
+//!
+//! Guest paths are normalized before they get here, so `..` can't climb out
+//! of the root. Host paths are canonicalized and must stay inside the root,
+//! which stops symlinks from escaping it, and only regular files are handed
+//! to the guest. The host is never written: in overlay mode guest writes and
+//! new files only live in the in-memory layer of the `Vfs`.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// How guest writes to the host root are handled
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HostMode {
+    /// Host files can't be opened for writing and no files can be created
+    ReadOnly,
+
+    /// Writes and new files go to an in-memory layer over the host files
+    Overlay,
+}
+
+/// Why a host file can't be used
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HostError {
+    /// There is no such file
+    NotFound,
+
+    /// The rules deny the path, it leaves the root or is not a regular file
+    Denied,
+}
+
+/// A host directory mapped as the guest's root
+#[derive(Clone, Debug)]
+pub struct HostRoot {
+    /// Canonical path of the directory
+    dir: PathBuf,
+
+    /// How guest writes are handled
+    mode: HostMode,
+
+    /// Guest paths which may be used, everything if empty
+    allow: Vec<String>,
+
+    /// Guest paths which may never be used, taking priority over `allow`
+    deny: Vec<String>,
+}
+
+/// Check if the normalized `path` is `prefix` or inside it
+fn under(path: &str, prefix: &str) -> bool {
+    prefix.is_empty() || path == prefix ||
+        path.strip_prefix(prefix).is_some_and(|rest| rest.starts_with('/'))
+}
+
+impl HostRoot {
+    /// Map the host directory `dir` as the guest's root
+    pub fn new(dir: impl AsRef<Path>, mode: HostMode) -> io::Result<Self> {
+        let dir = std::fs::canonicalize(dir)?;
+        if !dir.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "host root is not a directory"));
+        }
+
+        Ok(HostRoot { dir, mode, allow: Vec::new(), deny: Vec::new() })
+    }
+
+    /// How guest writes are handled
+    pub fn mode(&self) -> HostMode {
+        self.mode
+    }
+
+    /// Allow the guest to use `path` and everything below it. Once any path
+    /// is allowed, paths which are not are denied
+    pub fn allow(&mut self, path: &str) {
+        self.allow.push(crate::vfs::normalize(path.as_bytes()).unwrap());
+    }
+
+    /// Deny the guest `path` and everything below it
+    pub fn deny(&mut self, path: &str) {
+        self.deny.push(crate::vfs::normalize(path.as_bytes()).unwrap());
+    }
+
+    /// Check if the rules let the guest use the normalized `path`
+    pub fn permits(&self, path: &str) -> bool {
+        !self.deny.iter().any(|x| under(path, x)) &&
+            (self.allow.is_empty() || self.allow.iter().any(|x| under(path, x)))
+    }
+
+    /// Find the host file for the normalized guest `path`. Symlinks are
+    /// followed, and the rules are checked against both `path` and where it
+    /// really leads
+    pub fn resolve(&self, path: &str) -> Result<PathBuf, HostError> {
+        if !self.permits(path) {
+            return Err(HostError::Denied);
+        }
+
+        let host = std::fs::canonicalize(self.dir.join(path))
+            .map_err(|_| HostError::NotFound)?;
+        let real = host.strip_prefix(&self.dir).map_err(|_| HostError::Denied)?
+            .to_str().ok_or(HostError::Denied)?;
+        if !self.permits(real) {
+            return Err(HostError::Denied);
+        }
+
+        // Devices, pipes and directories are never handed out
+        if !std::fs::metadata(&host).map_err(|_| HostError::NotFound)?.is_file() {
+            return Err(HostError::Denied);
+        }
+
+        Ok(host)
+    }
+}
@@ -0,0 +1,156 @@
+//! The guest's stdin stream
+//!
+//! Bytes come from a pluggable source: the host's stdin, a byte buffer, a
+//! file or a TCP socket. Reads follow Linux pipe semantics: at most `count`
+//! bytes are returned, anything more read from the source is kept for the
+//! next read, and 0 means end of file.
+//!
+//! Line mode is an opt-in for guests written against the original
+//! behaviour: every read returns one whole line, whatever `count` is, with
+//! the newline replaced by a NUL.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Cursor, Read};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Bytes asked of the source at once
+const CHUNK_SIZE: usize = 4096;
+
+/// An input stream shared by an emulator and its forks
+pub type InputHandle = Arc<Mutex<Input>>;
+
+/// A stream of input for the guest
+pub struct Input {
+    /// Where the bytes come from
+    source: Box<dyn Read + Send>,
+
+    /// Bytes read from the source which the guest has not read yet
+    pending: VecDeque<u8>,
+
+    /// Set once the source has no more bytes
+    eof: bool,
+
+    /// Return a NUL terminated line per read instead of at most `count`
+    /// bytes
+    line_mode: bool,
+
+    /// Set if the last line read ended in a newline, replaced by the NUL
+    newline: bool,
+}
+
+impl Input {
+    /// Create a stream reading from `source`
+    pub fn new(source: impl Read + Send + 'static) -> Self {
+        Input {
+            source:    Box::new(source),
+            pending:   VecDeque::new(),
+            eof:       false,
+            line_mode: false,
+            newline:   false,
+        }
+    }
+
+    /// Create a stream reading the host's stdin
+    pub fn stdin() -> Self {
+        Input::new(io::stdin())
+    }
+
+    /// Create a stream of `bytes`
+    pub fn bytes(bytes: Vec<u8>) -> Self {
+        Input::new(Cursor::new(bytes))
+    }
+
+    /// Create a stream reading the file at `path`
+    pub fn file(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Input::new(File::open(path)?))
+    }
+
+    /// Create a stream reading from a TCP connection to `addr`
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Input::new(TcpStream::connect(addr)?))
+    }
+
+    /// Turn line mode on or off
+    pub fn set_line_mode(&mut self, line_mode: bool) {
+        self.line_mode = line_mode;
+    }
+
+    /// Check if line mode is on
+    pub fn line_mode(&self) -> bool {
+        self.line_mode
+    }
+
+    /// Read the next chunk of the source into `pending`, returning `false`
+    /// at end of file
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.eof {
+            return Ok(false);
+        }
+
+        let mut chunk = [0u8; CHUNK_SIZE];
+        let n = loop {
+            match self.source.read(&mut chunk) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                result => break result?,
+            }
+        };
+
+        self.eof = n == 0;
+        self.pending.extend(&chunk[..n]);
+        Ok(n > 0)
+    }
+
+    /// Read as the guest's `read(0, buf, count)` does. Waits for the source
+    /// only if nothing is pending. In line mode `count` is ignored
+    pub fn read(&mut self, count: usize) -> io::Result<Vec<u8>> {
+        if self.line_mode {
+            return self.read_line();
+        }
+
+        if count > 0 && self.pending.is_empty() {
+            self.fill()?;
+        }
+
+        let n = count.min(self.pending.len());
+        Ok(self.pending.drain(..n).collect())
+    }
+
+    /// Read up to and including the next newline, replaced by a NUL
+    fn read_line(&mut self) -> io::Result<Vec<u8>> {
+        let mut end = 0;
+        let len = loop {
+            if let Some(pos) = self.pending.iter().skip(end).position(|&x| x == b'\n') {
+                break end + pos + 1;
+            }
+            end = self.pending.len();
+            if !self.fill()? {
+                break end;
+            }
+        };
+
+        let mut line: Vec<u8> = self.pending.drain(..len).collect();
+        self.newline = line.last() == Some(&b'\n');
+        if self.newline {
+            *line.last_mut().unwrap() = 0;
+        }
+        Ok(line)
+    }
+
+    /// Put back `data`, the bytes returned by the last `read`, so the next
+    /// read returns them again
+    pub fn unread(&mut self, data: &[u8]) {
+        let mut data = data.to_vec();
+        if self.line_mode && self.newline {
+            if let Some(last) = data.last_mut() {
+                *last = b'\n';
+            }
+        }
+
+        for &x in data.iter().rev() {
+            self.pending.push_front(x);
+        }
+    }
+}
@@ -2,11 +2,38 @@
 pub mod primitive;
 pub mod mmu;
 pub mod emulator;
-
-use std::io::{self, Write};
+pub mod mmio;
+pub mod taint;
+pub mod snapshot;
+pub mod trace;
+pub mod shadow;
+pub mod region;
+pub mod elf;
+pub mod embed;
+pub mod stack;
+pub mod symbols;
+pub mod firmware;
+pub mod bundle;
+pub mod syscall;
+pub mod vfs;
+pub mod hostfs;
+pub mod input;
+
+use std::io::Write;
 use emulator::{Emulator, Register, VmExit};
-use mmu::{VirtAddr, Perm, Sections, PERM_READ, PERM_WRITE, PERM_EXEC};
-use rand::Rng;
+use mmu::{VirtAddr, Perm, MAX_MEMORY_SIZE, PERM_READ, PERM_WRITE, PERM_EXEC};
+use symbols::Symbols;
+use elf::Elf;
+use firmware::{Firmware, Format};
+use bundle::Metadata;
+use std::sync::OnceLock;
+use syscall::{Syscalls, UnknownPolicy};
+use vfs::Target;
+use hostfs::{HostMode, HostRoot};
+use input::Input;
+use std::sync::Arc;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
 use std::time::SystemTime;
 
 const  VERBOSE_PRINTS: bool = true;
@@ -19,6 +46,34 @@ const TIME: [u64; 512] = [4268402577, 2885444848, 6069527499, 3145231422, 435446
 
 static mut KEY_INDEX: u32 = 0;
 
+/// Key and time tables of a bundle, replacing `KEYS` and `TIME`
+static TABLES: OnceLock<(Vec<String>, Vec<u64>)> = OnceLock::new();
+
+/// Number of entries in the key and time tables
+fn table_len() -> usize {
+    TABLES.get().map_or(KEYS.len(), |(keys, _)| keys.len())
+}
+
+/// rc4 key for the guest's output at `idx`
+fn key(idx: usize) -> &'static [u8] {
+    match TABLES.get() {
+        Some((keys, _)) => keys[idx].as_bytes(),
+        None            => KEYS[idx].as_bytes(),
+    }
+}
+
+/// Offset added to the time of day at `idx`
+fn time(idx: usize) -> u64 {
+    TABLES.get().map_or(TIME[idx], |(_, times)| times[idx])
+}
+
+/// Use the key and time tables of `metadata`, if it has any
+fn install_tables(metadata: &Metadata) {
+    if !metadata.keys.is_empty() {
+        let _ = TABLES.set((metadata.keys.clone(), metadata.times.clone()));
+    }
+}
+
 fn rc4<'a>(data: &'a [u8], key: &'a [u8], out: &'a mut Vec<u8>){
 
     let mut state: [u8; 256] = [0; 256];
@@ -47,7 +102,7 @@ fn rc4<'a>(data: &'a [u8], key: &'a [u8], out: &'a mut Vec<u8>){
 
         let tmp = state[(state[idx as usize].wrapping_add(state[idx2 as usize])) as usize];
 
-        out.push( *x ^ tmp as u8);
+        out.push( *x ^ tmp);
 
     }
     
@@ -55,262 +110,587 @@ fn rc4<'a>(data: &'a [u8], key: &'a [u8], out: &'a mut Vec<u8>){
 }
 
 
-fn handle_syscall(emu: &mut Emulator) -> Result<(), VmExit> {
-    let num = emu.reg(Register::A7);
+/// newlib's legacy `open`, used by the guest to open its files
+const SYS_OPEN: u64 = 1024;
+
+/// `write` to stdout and stderr, encrypted with the rc4 key of this run
+fn challenge_write(emu: &mut Emulator) -> Result<(), VmExit> {
+    let fd  = emu.reg(Register::A0);
+    let buf = emu.reg(Register::A1);
+    let len = emu.reg(Register::A2);
+
+    if let Some(Target::Stdout) | Some(Target::Stderr) = emu.vfs.target(fd) {
+        // writes to stdout and stderr, `-EFAULT` for a bad buffer
+        let bytes = match emu.memory.peek(VirtAddr(buf as usize),
+                                          len as usize, Perm(PERM_READ)) {
+            Ok(bytes) => bytes,
+            Err(_) => {
+                emu.set_reg(Register::A0, syscall::EFAULT.wrapping_neg());
+                return Ok(());
+            }
+        };
+
+        if VERBOSE_PRINTS {
+            if let Ok(st) = core::str::from_utf8(bytes){
+                unsafe {
+                    let key = key(KEY_INDEX as usize);
+                    let mut out = Vec::new();
+                    rc4(st.as_bytes(), key, &mut out);
+
+                    std::io::stdout().write_all(&out[..]).unwrap();
+                    std::io::stdout().flush().unwrap();
+                    println!();
+                }
+            }
+        }
+        emu.set_reg(Register::A0, len);
+    } else {
+        return syscall::write(emu);
+    }
+    Ok(())
+}
+
+/// `gettimeofday` offset by the time of this run, the guest seeds `srand`
+/// with it
+fn challenge_gettimeofday(emu: &mut Emulator) -> Result<(), VmExit> {
+    let  timeval  = emu.reg(Register::A0);
+
+    let tv_sec = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
 
-      match num {
-          214 => {
-              // brk()
-              let req_base = emu.reg(Register::A0) as i64;
-              let cur_base = emu.memory.allocate(0).unwrap();
+    let result = unsafe {
+        let time = time(KEY_INDEX as usize).wrapping_add(tv_sec);
+        emu.memory.write::<u64>(VirtAddr(timeval as usize), time)
+    };
 
-              let increment = if req_base !=0 {
-                  (req_base as i64).checked_sub(cur_base.0 as i64)
-                  .ok_or(VmExit::SyscallIntegerOverflow)?
-              } else {
-                  0
-              };
+    // Setting the returned value as success, or `-EFAULT` for a bad `tv`
+    emu.set_reg(Register::A0, match result {
+        Ok(()) => 0,
+        Err(_) => syscall::EFAULT.wrapping_neg(),
+    });
+    Ok(())
+}
 
-              // We don't handle negative brks yet
-              assert!(increment >= 0);
+/// Give the guest the challenge's files. The guest opens the flag as
+/// `readme`
+fn challenge_files(emu: &mut Emulator) {
+    emu.vfs.add_host_file("readme", "flag");
+}
 
-              // Attempt to extend data section by increment
-              if let Some(_base) = emu.memory.allocate(increment as usize) {
-                  let new_base = cur_base.0 + increment as usize;
-                  emu.set_reg(Register::A0, new_base as u64);
-              } else {
-                  emu.set_reg(Register::A0, !0);
-              }
+/// The challenge reads stdin a line at a time, whatever size the guest asks
+/// for
+const CHALLENGE_LINE_MODE: bool = true;
+
+/// The Linux system calls with the challenge's own on top
+fn challenge_syscalls() -> Syscalls {
+    let mut syscalls = Syscalls::linux();
+    syscalls.register(syscall::SYS_WRITE,        challenge_write);
+    syscalls.register(syscall::SYS_GETTIMEOFDAY, challenge_gettimeofday);
+    syscalls.register(SYS_OPEN,                  syscall::open);
+    syscalls
+}
 
-              Ok(())
-          },
-          63 => {
-              // read
-              let fd    = emu.reg(Register::A0);
-              let buf   = emu.reg(Register::A1);
-              let _count = emu.reg(Register::A2);
+/// Command line options
+#[derive(Default)]
+struct Options {
+    /// Save a snapshot to this path when the guest first reads stdin
+    save_snapshot: Option<String>,
 
-              //println!("in read, buf ={:#x}, count= {:#x}", buf, count);
+    /// Resume from this snapshot instead of booting the guest
+    load_snapshot: Option<String>,
 
-              if fd == 0 {
-                  let mut buffer = String::new();
+    /// Print the memory changes made by the guest when it exits
+    diff: bool,
 
-                  if let Ok(n) = io::stdin().read_line(&mut buffer) {
-                      emu.set_reg(Register::A0, n as u64);
-                  }
-                  
-                  // replacing newline with null-bytes
-                  let buffer = buffer.replace("\n", "\x00");
-                  emu.memory.write_from(VirtAddr(buf as usize), buffer.as_bytes()).unwrap();
-
-              } else if fd == 1010 {
-                  let content = std::fs::read("readme").ok().unwrap();
-                  emu.memory.write_from(VirtAddr(buf as usize), &content).unwrap();
-                  emu.set_reg(Register::A0, content.len() as u64);
-            
-              } else if fd == 2020 {
-                  let content = std::fs::read("flag").ok().unwrap();
-                  emu.memory.write_from(VirtAddr(buf as usize), &content).unwrap();
-                  emu.set_reg(Register::A0, content.len() as u64);
-              } 
-              else {
-                  println!("Not handling = {}", fd);
-                  emu.set_reg(Register::A0, !0);
+    /// Check calls and returns against a shadow call stack
+    shadow_stack: bool,
 
-              }
-              Ok(())
-
-          }
-          64 => {
-              // write
-              let fd  = emu.reg(Register::A0);
-              let buf = emu.reg(Register::A1);
-              let len = emu.reg(Register::A2);
-
-              if fd == 1 || fd == 2 {
-                  // writes to stdout and stderr
-                  let bytes = emu.memory.peek(VirtAddr(buf as usize),
-                  len as usize, Perm(PERM_READ))?;
-                  
-                  if VERBOSE_PRINTS {
-                      if let Ok(st) = core::str::from_utf8(bytes){
-                          unsafe {
-                              let key = KEYS[KEY_INDEX as usize];
-                              //println!("{}, key : {}, KEY_INDEX : {}", st, key, KEY_INDEX);
-                              let mut out = Vec::new();
-                              rc4(st.as_bytes(), key.as_bytes(), &mut out);
-
-                              std::io::stdout().write_all(&out[..]).unwrap();
-                              std::io::stdout().flush().unwrap();
-                              println!();
-                            
+    /// Print the guest loads and stores touching these `(address, size)`
+    /// ranges
+    traces: Vec<(usize, usize)>,
+
+    /// Size of the guest stack in bytes, `DEFAULT_STACK_SIZE` if not given
+    stack_size: Option<usize>,
+
+    /// Size of guest memory in bytes, overriding the bundle and the size
+    /// the guest needs
+    memory_size: Option<usize>,
+
+    /// File holding the guest, either alone or appended to a host
+    guest: Option<String>,
+
+    /// Environment of the guest as `KEY=VALUE` strings
+    env: Vec<String>,
+
+    /// Arguments passed to the guest after its name
+    args: Vec<String>,
+
+    /// Seed for the guest's `AT_RANDOM` bytes, random if not given
+    seed: Option<u64>,
+
+    /// Format of a bare-metal firmware guest, the guest is an ELF if not given
+    format: Option<Format>,
+
+    /// Load address of a flat firmware guest
+    base: Option<usize>,
+
+    /// Entry point of a firmware guest, overriding the one in the image
+    entry: Option<usize>,
+
+    /// Permissions of parts of a firmware guest as `(address, size, perm)`,
+    /// the rest of the image is readable, writable and executable
+    perms: Vec<(usize, usize, Perm)>,
+
+    /// Host directory the guest sees as its root
+    root: Option<String>,
+
+    /// Let guest writes to the root go to memory instead of failing
+    overlay: bool,
+
+    /// Guest paths under the root the guest may use
+    allow: Vec<String>,
+
+    /// Guest paths under the root the guest may never use
+    deny: Vec<String>,
+
+    /// Where the guest's stdin comes from, the host's stdin if not given
+    stdin: Option<String>,
+
+    /// Read stdin a line at a time, `CHALLENGE_LINE_MODE` if not given
+    line_mode: Option<bool>,
+
+    /// What to do with system calls without a handler
+    unknown_syscall: UnknownPolicy,
+}
+
+impl Options {
+    /// Parse the options from the process arguments
+    fn parse() -> Self {
+        let mut opts = Options::default();
+        let mut args = std::env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--save-snapshot" => opts.save_snapshot = args.next(),
+                "--load-snapshot" => opts.load_snapshot = args.next(),
+                "--diff"          => opts.diff = true,
+                "--shadow-stack"  => opts.shadow_stack = true,
+                "--guest"         => opts.guest = args.next(),
+                "--root"          => opts.root = args.next(),
+                "--overlay"       => opts.overlay = true,
+                "--allow"         => opts.allow.extend(args.next()),
+                "--deny"          => opts.deny.extend(args.next()),
+                "--env"           => opts.env.extend(args.next()),
+                "--stdin"         => opts.stdin = args.next(),
+                "--unknown-syscall" => {
+                    opts.unknown_syscall = match args.next().as_deref() {
+                        Some("enosys") => UnknownPolicy::Enosys,
+                        Some("exit")   => UnknownPolicy::Exit,
+                        Some("log")    => UnknownPolicy::Log,
+                        _ => {
+                            eprintln!("--unknown-syscall needs enosys, exit or log");
+                            std::process::exit(1);
+                        }
+                    };
+                }
+                "--stdin-mode"    => {
+                    opts.line_mode = match args.next().as_deref() {
+                        Some("line") => Some(true),
+                        Some("raw")  => Some(false),
+                        _ => {
+                            eprintln!("--stdin-mode needs line or raw");
+                            std::process::exit(1);
+                        }
+                    };
+                }
+                "--seed"          => {
+                    opts.seed = args.next().and_then(|x| x.parse().ok());
+                    if opts.seed.is_none() {
+                        eprintln!("--seed needs a number");
+                        std::process::exit(1);
+                    }
+                }
+                // Everything after `--` is passed to the guest
+                "--"              => opts.args.extend(args.by_ref()),
+                "--stack-size"    => {
+                    opts.stack_size = args.next().and_then(|x| parse_size(&x));
+                    if opts.stack_size.is_none() {
+                        eprintln!("--stack-size needs a size in bytes");
+                        std::process::exit(1);
+                    }
+                }
+                "--memory-size"   => {
+                    opts.memory_size = args.next().and_then(|x| parse_size(&x))
+                        .filter(|&x| x > 0 && x <= MAX_MEMORY_SIZE);
+                    if opts.memory_size.is_none() {
+                        eprintln!("--memory-size needs a size in bytes up to {:#x}",
+                                  MAX_MEMORY_SIZE);
+                        std::process::exit(1);
+                    }
+                }
+                "--format"        => {
+                    opts.format = args.next().and_then(|x| Format::from_name(&x));
+                    if opts.format.is_none() {
+                        eprintln!("--format needs one of flat, ihex or srec");
+                        std::process::exit(1);
+                    }
+                }
+                "--base"          => {
+                    opts.base = args.next().and_then(|x| parse_size(&x));
+                    if opts.base.is_none() {
+                        eprintln!("--base needs an address");
+                        std::process::exit(1);
+                    }
+                }
+                "--entry"         => {
+                    opts.entry = args.next().and_then(|x| parse_size(&x));
+                    if opts.entry.is_none() {
+                        eprintln!("--entry needs an address");
+                        std::process::exit(1);
+                    }
+                }
+                "--trace"         => {
+                    match args.next().and_then(|x| parse_range(&x)) {
+                        Some(range) => opts.traces.push(range),
+                        None => {
+                            eprintln!("--trace needs ADDR:SIZE");
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                "--perm"          => {
+                    match args.next().and_then(|x| parse_perm_range(&x)) {
+                        Some(range) => opts.perms.push(range),
+                        None => {
+                            eprintln!("--perm needs ADDR:SIZE:[r][w][x]");
+                            std::process::exit(1);
                         }
+                    }
+                }
+                _ => {
+                    eprintln!("Unknown argument {}", arg);
+                    std::process::exit(1);
+                }
+            }
+        }
 
-                      }
-                  }
-                  emu.set_reg(Register::A0, len);
-              } else {
-                  // Unknown FD
-                  emu.set_reg(Register::A0, !0);
-              }
-              Ok(())
+        // Firmware is read as is, never out of the host
+        if opts.format.is_some() && opts.guest.is_none() {
+            eprintln!("--format needs the firmware passed with --guest");
+            std::process::exit(1);
+        }
+        if opts.format != Some(Format::Flat) && opts.base.is_some() {
+            eprintln!("--base only applies to --format flat");
+            std::process::exit(1);
+        }
 
-          }
-          80 => {
-              //fstat
-              let _fd          = emu.reg(Register::A0);
-              let mut statbuf = emu.reg(Register::A1) as usize;
+        if opts.root.is_none() && (opts.overlay || !opts.allow.is_empty() ||
+                                   !opts.deny.is_empty()) {
+            eprintln!("--overlay, --allow and --deny need --root");
+            std::process::exit(1);
+        }
 
-              // writing 0 to `stat` buffer
-              // size is not known
-              for _ in 0..0x90 {
-                  emu.memory.write::<u8>(VirtAddr(statbuf), 0).unwrap();
-                  statbuf += 1
+        // Snapshots hold their own memory
+        if opts.memory_size.is_some() && opts.load_snapshot.is_some() {
+            eprintln!("--memory-size can't be used with --load-snapshot");
+            std::process::exit(1);
+        }
 
-              }
-              
-              // Setting the returned value as success
-              emu.set_reg(Register::A0, 0);
-
-              Ok(())
-          },
-          169 => {
-              //gettimeofday
-              let  timeval  = emu.reg(Register::A0);
-              let _timezone = emu.reg(Register::A1);
-
-              let tv_sec = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
-
-              // The value returned by this will be used in srand()
-              unsafe {
-                  let time = TIME[KEY_INDEX as usize].wrapping_add(tv_sec);
-                  emu.memory.write::<u64>(VirtAddr(timeval as usize), time).unwrap();
-              }
+        // Snapshots don't hold the calls made before they were taken
+        if opts.shadow_stack && opts.load_snapshot.is_some() {
+            eprintln!("--shadow-stack can't be used with --load-snapshot");
+            std::process::exit(1);
+        }
 
-              // Setting the returned value as success
-              emu.set_reg(Register::A0, 0);
-              Ok(())
-          },
-          1024 => {
-              // Open()
-              let filename = emu.reg(Register::A0) as usize;
-              let _flag    = emu.reg(Register::A1);
-              let _mode    = emu.reg(Register::A2);
-
-              // Determine the length of filename
-              let mut fnlen = 0;
-              while emu.memory.read::<u8>(VirtAddr(filename + fnlen))? != 0 {
-                  fnlen += 1;
-              }
+        opts
+    }
+}
 
-              // get the filename bytes
-              let bytes = emu.memory.peek(VirtAddr(filename), fnlen, Perm(PERM_READ))?;
+/// Open the guest's stdin from `-` for the host's stdin, `file:PATH`,
+/// `tcp:HOST:PORT` or `bytes:TEXT`
+fn open_input(spec: &str) -> std::io::Result<Input> {
+    if spec == "-" {
+        Ok(Input::stdin())
+    } else if let Some(path) = spec.strip_prefix("file:") {
+        Input::file(path)
+    } else if let Some(addr) = spec.strip_prefix("tcp:") {
+        Input::connect(addr)
+    } else if let Some(text) = spec.strip_prefix("bytes:") {
+        Ok(Input::bytes(text.as_bytes().to_vec()))
+    } else {
+        Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+            "expected -, file:PATH, tcp:HOST:PORT or bytes:TEXT"))
+    }
+}
 
-              if bytes == b"readme" {
-                  emu.set_reg(Register::A0, 2020);
-              }
-              else {
-                  // Unknown filename
-                  println!("Unkown filename:  {:?}", core::str::from_utf8(bytes));
-                  emu.set_reg(Register::A0, !0);
+/// Parse a size given in decimal or in hex with a `0x` prefix
+fn parse_size(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None      => s.parse().ok(),
+    }
+}
 
-              }
+/// Parse an `ADDR:SIZE` range
+fn parse_range(s: &str) -> Option<(usize, usize)> {
+    let (addr, size) = s.split_once(':')?;
+    Some((parse_size(addr)?, parse_size(size)?))
+}
 
-              Ok(())
-          }
-          62 => {
-              // lseek()
-              let _fd     = emu.reg(Register::A0);
-              let _offset = emu.reg(Register::A1);
-              let _whence = emu.reg(Register::A2);
-              
-              // Not implemented
-
-              Ok(())
-          }
-          57 => {
-              // Close()
-
-              //Just return success for now
-              emu.set_reg(Register::A0, 0);
-              Ok(())
-          }
-          93 => {
-              // exit()
-              Err(VmExit::Exit)
-          }
-          _ => {
-              unimplemented!("syscall {} at {:x}", num, emu.reg(Register::Pc));
-          }
-      }
+/// Parse an `ADDR:SIZE:PERM` range, where `PERM` is any of `r`, `w` and `x`
+fn parse_perm_range(s: &str) -> Option<(usize, usize, Perm)> {
+    let mut parts = s.split(':');
+    let addr = parse_size(parts.next()?)?;
+    let size = parse_size(parts.next()?)?;
+
+    let mut perm = 0;
+    for flag in parts.next()?.chars() {
+        perm |= match flag {
+            'r' => PERM_READ,
+            'w' => PERM_WRITE,
+            'x' => PERM_EXEC,
+            '-' => 0,
+            _   => return None,
+        };
+    }
+
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((addr, size, Perm(perm)))
 }
 
-// Emulator is based on gamozolabs https://www.youtube.com/watch?v=iM3s8-umRO0  
-fn main() {
+/// Size of guest memory unless the bundle gives one. Firmware gets more if
+/// its image and stack need it
+const DEFAULT_MEMORY_SIZE: usize = 64 * 1024 * 1024;
+
+/// Size of the guest stack unless given with `--stack-size`
+const DEFAULT_STACK_SIZE: usize = 32 * 1024;
+
+/// Size of the unmapped guard region below the guest stack
+const STACK_GUARD_SIZE: usize = 64 * 1024;
+
+/// Read the guest executable and its challenge settings out of `path`, or the
+/// first file holding one of the running executable and `./rustmeup`. Exits
+/// if there is none, or if a bundle fails its integrity check
+fn read_guest(path: Option<&str>) -> (Vec<u8>, Metadata) {
+    let candidates = match path {
+        Some(path) => vec![path.into()],
+        None => {
+            let mut candidates: Vec<std::path::PathBuf> =
+                std::env::current_exe().into_iter().collect();
+            candidates.push("./rustmeup".into());
+            candidates
+        }
+    };
+
+    for candidate in &candidates {
+        if let Ok(contents) = std::fs::read(candidate) {
+            match bundle::parse(&contents) {
+                Some(Ok(bundle)) => {
+                    return (bundle.guest.to_vec(), bundle.metadata);
+                }
+                Some(Err(err)) => {
+                    eprintln!("{:?} failed its integrity check: {}", candidate, err);
+                    std::process::exit(1);
+                }
+                None => {}
+            }
+
+            if let Some((_, guest)) = embed::find_guest(&contents) {
+                return (guest.to_vec(), Metadata::default());
+            }
+        }
+    }
 
-    let mut emu = Emulator::new(64 * 1024 * 1024);
+    eprintln!("No guest executable found in {:?}", candidates);
+    std::process::exit(1);
+}
 
-    unsafe {
-        KEY_INDEX = rand::thread_rng().gen_range(0..512);
-   }
+/// Name the guest code at `addr` for reports, empty without symbols
+fn describe(emu: &Emulator, addr: usize) -> String {
+    emu.locate_code(VirtAddr(addr))
+        .map(|x| format!(" {}", x)).unwrap_or_default()
+}
+
+/// Load the guest ELF into `emu` and set up its entry state as given by
+/// `opts`
+fn boot(emu: &mut Emulator, guest: &[u8], opts: &Options) {
+    let stack_size = opts.stack_size.unwrap_or(DEFAULT_STACK_SIZE);
+
+    // Load the guest ELF, which also sets the entry point
+    let elf = emu.load_elf(guest).unwrap_or_else(|err| {
+        eprintln!("Failed to load the guest: {}", err);
+        std::process::exit(1);
+    });
+    if elf.base != 0 {
+        eprintln!("Position independent guest loaded at {:#x}", elf.base);
+    }
 
+    // Set up a stack with a guard below it to catch overflows
+    let stack = emu.memory.allocate_stack(stack_size, STACK_GUARD_SIZE)
+        .expect("Failed to allocate a stack");
 
-    let rust_off = 3424440;
+    emu.set_reg(Register::Sp, (stack.0 + stack_size) as u64);
 
-    emu.memory.load(
-        "./rustmeup",&[
-        Sections{
-            file_offset: 0x000000000000000 + rust_off,
-            virt_addr: VirtAddr(0x10000),
-            file_size: 0x00000000001db50,
-            mem_size: 0x000000000001db50, 
-            permissions: Perm(PERM_READ | PERM_EXEC),
-        },
+    // Build the argv, envp and auxv the guest's libc expects
+    let argv: Vec<&[u8]> = std::iter::once("rustmeup")
+        .chain(opts.args.iter().map(|x| x.as_str()))
+        .map(|x| x.as_bytes()).collect();
+    let envp: Vec<&[u8]> = opts.env.iter().map(|x| x.as_bytes()).collect();
+    let random: [u8; 16] = match opts.seed {
+        Some(seed) => StdRng::seed_from_u64(seed).gen(),
+        None       => rand::thread_rng().gen(),
+    };
 
-        Sections{
-            file_offset: 0x000000000001db50 + rust_off,
-            virt_addr: VirtAddr(0x2eb50),
-            file_size: 0x00000000000120a,
-            mem_size: 0x00000000000012a0, 
-            permissions: Perm(PERM_READ | PERM_WRITE),
-        },
-        ]);
+    stack::init_stack(emu, &elf, &argv, &envp, &random)
+        .expect("Failed to set up the initial stack");
+}
 
-        // Set the program entry point 
-        emu.set_reg(Register::Pc, 0x100c8);
+/// Parse the bare-metal firmware guest, applying the permissions and entry
+/// point given by `opts`
+fn parse_firmware(format: Format, guest: &[u8], opts: &Options) -> Firmware {
+    let base = VirtAddr(opts.base.unwrap_or(0));
+    let mut firmware = Firmware::parse(format, guest, base,
+            Perm(PERM_READ | PERM_WRITE | PERM_EXEC))
+        .expect("Failed to parse the firmware");
+
+    for &(addr, size, perm) in &opts.perms {
+        if firmware.protect(VirtAddr(addr), size, perm).is_none() {
+            eprintln!("--perm {:#x}:{:#x} is outside the firmware", addr, size);
+            std::process::exit(1);
+        }
+    }
+    if let Some(entry) = opts.entry {
+        firmware.entry = VirtAddr(entry);
+    }
+    firmware
+}
 
-        // Set up a stack
-        let stack_size: u64 = 32 * 1024; 
-        let stack = emu.memory.allocate(stack_size as usize)
-            .expect("Failed to allocate a stack");
+/// Size of guest memory holding `firmware` and the stack after it, at least
+/// `DEFAULT_MEMORY_SIZE`
+fn firmware_memory_size(firmware: &Firmware, opts: &Options) -> usize {
+    let stack_size = opts.stack_size.unwrap_or(DEFAULT_STACK_SIZE);
+    let size = firmware.end().checked_add(0xfff).map(|x| x & !0xfff)
+        .and_then(|x| x.checked_add(STACK_GUARD_SIZE))
+        .and_then(|x| x.checked_add(stack_size))
+        .filter(|&x| x <= MAX_MEMORY_SIZE)
+        .unwrap_or_else(|| {
+            eprintln!("The firmware and its stack need more than {:#x} bytes \
+                       of memory", MAX_MEMORY_SIZE);
+            std::process::exit(1);
+        });
+    size.max(DEFAULT_MEMORY_SIZE)
+}
 
-        emu.set_reg(Register::Sp, stack.0 as u64 + stack_size);
+/// Load the bare-metal `firmware` guest into `emu`. Firmware sets up its own
+/// environment, it only gets a stack
+fn boot_firmware(emu: &mut Emulator, firmware: &Firmware, opts: &Options) {
+    emu.load_firmware(firmware).unwrap_or_else(|err| {
+        eprintln!("Failed to load the firmware: {}", err);
+        std::process::exit(1);
+    });
+    for segment in &firmware.segments {
+        eprintln!("Firmware segment {:#x}..{:#x} {}", segment.virt_addr.0,
+                  segment.virt_addr.0 + segment.mem_size, segment.permissions);
+    }
 
+    let stack_size = opts.stack_size.unwrap_or(DEFAULT_STACK_SIZE);
+    let stack = emu.memory.allocate_stack(stack_size, STACK_GUARD_SIZE)
+        .expect("Failed to allocate a stack");
+    emu.set_reg(Register::Sp, (stack.0 + stack_size) as u64);
+}
 
-        let app_name = emu.memory.allocate(4096)
-            .expect("Failed to allocate");
-        emu.memory.write_from(app_name, b"rustmeup\0")
-            .unwrap();
+// Emulator is based on gamozolabs https://www.youtube.com/watch?v=iM3s8-umRO0  
+fn main() {
 
+    let mut opts = Options::parse();
 
-        macro_rules! push{
-            ($expr:expr) => {
-                let sp = emu.reg(Register::Sp) - 
-                    core::mem::size_of_val(&$expr) as u64;
-                emu.memory.write(VirtAddr(sp as usize), $expr)
-                    .expect("Push failed\n");
-                emu.set_reg(Register::Sp, sp);
+    let mut emu = if let Some(path) = &opts.load_snapshot {
+        let mut emu = Emulator::load_snapshot(path)
+            .expect("Failed to load snapshot");
+
+        // Snapshots don't hold symbols, take them from the given guest
+        if let (None, Some(path)) = (&opts.format, &opts.guest) {
+            let (guest, metadata) = read_guest(Some(path));
+            install_tables(&metadata);
+            let base = Elf::parse(&guest).map_or(0, |x| x.base);
+            emu.set_symbols(Arc::new(Symbols::parse(&guest, base)));
+        }
+        emu
+    } else {
+        let (guest, metadata) = match (&opts.format, &opts.guest) {
+            (Some(_), Some(path)) => {
+                let firmware = std::fs::read(path).unwrap_or_else(|err| {
+                    eprintln!("Failed to read the firmware {}: {}", path, err);
+                    std::process::exit(1);
+                });
+                (firmware, Metadata::default())
             }
+            _ => read_guest(opts.guest.as_deref()),
+        };
+        install_tables(&metadata);
+
+        let firmware = opts.format.map(|x| parse_firmware(x, &guest, &opts));
+        let memory_size = opts.memory_size
+            .or_else(|| metadata.memory_size.map(|x| x as usize))
+            .or_else(|| firmware.as_ref().map(|x| firmware_memory_size(x, &opts)))
+            .unwrap_or(DEFAULT_MEMORY_SIZE);
+        let mut emu = Emulator::new(memory_size);
+        match &firmware {
+            Some(firmware) => boot_firmware(&mut emu, firmware, &opts),
+            None           => boot(&mut emu, &guest, &opts),
         }
+        if let Some(entry) = metadata.entry {
+            emu.set_reg(Register::Pc, entry);
+        }
+        emu
+    };
 
-        push!(0u64);   // Auxp
-        push!(0u64);   // Envp
-        push!(0u64);   // Argv end
-        push!(app_name.0); // Argv
-        push!(1u64);   // Argc
+    unsafe {
+        KEY_INDEX = rand::thread_rng().gen_range(0..table_len()) as u32;
+   }
 
+    if let Some(dir) = &opts.root {
+        let mode = if opts.overlay { HostMode::Overlay } else { HostMode::ReadOnly };
+        let mut root = HostRoot::new(dir, mode).unwrap_or_else(|err| {
+            eprintln!("Failed to use {} as the guest root: {}", dir, err);
+            std::process::exit(1);
+        });
+        opts.allow.iter().for_each(|x| root.allow(x));
+        opts.deny.iter().for_each(|x| root.deny(x));
+        emu.vfs.set_root(root);
+    }
+
+    // Snapshots already hold the files the guest had
+    if opts.load_snapshot.is_none() {
+        challenge_files(&mut emu);
+    }
+
+    let mut input = match &opts.stdin {
+        Some(spec) => open_input(spec).unwrap_or_else(|err| {
+            eprintln!("Failed to open stdin {}: {}", spec, err);
+            std::process::exit(1);
+        }),
+        None => Input::stdin(),
+    };
+    input.set_line_mode(opts.line_mode.unwrap_or(CHALLENGE_LINE_MODE));
+    *emu.stdin.lock().unwrap() = input;
+
+    if opts.shadow_stack {
+        emu.enable_shadow_stack();
+    }
+    if !opts.traces.is_empty() {
+        emu.set_trace_log(std::io::stderr());
+        for &(addr, size) in &opts.traces {
+            emu.add_trace_filter(VirtAddr(addr), size);
+        }
+    }
 
-        loop {
+    let mut syscalls = challenge_syscalls();
+    syscalls.set_unknown_policy(opts.unknown_syscall);
+
+    // Keep the starting state around to diff against
+    let parent = if opts.diff { Some(emu.fork()) } else { None };
 
             let _vmexit = loop {
                 let vmexit = emu.run( )
@@ -318,20 +698,47 @@ fn main() {
 
               match vmexit {
                   VmExit::Syscall => {
-                      if let Err(vmexit) = handle_syscall(&mut emu){
+                      // Save before the first stdin read, so a restored
+                      // guest starts by re-issuing it
+                      if emu.reg(Register::A7) == syscall::SYS_READ &&
+                              emu.reg(Register::A0) == 0 {
+                          if let Some(path) = opts.save_snapshot.take() {
+                              emu.save_snapshot(&path)
+                                  .expect("Failed to save snapshot");
+                          }
+                      }
+
+                      if let Err(vmexit) = syscalls.dispatch(&mut emu) {
                           break vmexit;
                       }
                       // Advance PC
                       let pc = emu.reg(Register::Pc);
                       emu.set_reg(Register::Pc, pc.wrapping_add(4));
                   }
+                  VmExit::Watchpoint { addr, kind, pc } => {
+                      // Report and resume after the accessing instruction
+                      eprintln!("watchpoint {:#x} {:?} at pc {:#x}{}",
+                                addr.0, kind, pc.0, describe(&emu, pc.0));
+                  }
                 _ => break vmexit,
               }
           };
+            // Print the changes first, the crash report below doesn't return
+            if let Some(parent) = &parent {
+                for diff in emu.memory.diff(&parent.memory) {
+                    eprintln!("{}", diff);
+                }
+            }
+
             // print if err otherwise program will not exit
             if _vmexit != VmExit::Exit {
-                panic!(" {:#x} {:?}\n", emu.reg(Register::Pc), _vmexit);
+                for frame in emu.call_stack().unwrap_or(&[]).iter().rev() {
+                    eprintln!("  called from {:#x}{}", frame.call_site.0,
+                              describe(&emu, frame.call_site.0));
+                }
+                let pc = emu.reg(Register::Pc) as usize;
+                panic!(" {:#x}{} {}\n", pc, describe(&emu, pc), _vmexit);
             }
-        }
 
+    std::process::exit(emu.reg(Register::A0) as i32);
 }
@@ -0,0 +1,58 @@
+//! Memory mapped I/O regions whose accesses are routed to host devices
+
+use crate::emulator::VmExit;
+use crate::mmu::VirtAddr;
+use std::sync::{Arc, Mutex};
+
+/// A host side device backing a memory mapped region of the guest
+pub trait MmioDevice {
+    /// Handle a guest load of `buf.len()` bytes at `offset` into the region
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), VmExit>;
+
+    /// Handle a guest store of `buf` at `offset` into the region
+    fn write(&mut self, offset: usize, buf: &[u8]) -> Result<(), VmExit>;
+}
+
+/// A shareable handle to a device, forked MMUs talk to the same device
+pub type MmioHandle = Arc<Mutex<dyn MmioDevice + Send>>;
+
+/// A range of guest memory backed by a device
+#[derive(Clone)]
+pub struct MmioRegion {
+    /// First address of the region
+    pub start: VirtAddr,
+
+    /// One past the last address of the region
+    pub end: usize,
+
+    /// Device which services the accesses
+    pub device: MmioHandle,
+}
+
+impl MmioRegion {
+    /// Check if `size` bytes at `addr` touch this region
+    pub fn overlaps(&self, addr: VirtAddr, size: usize) -> bool {
+        addr.0 < self.end && self.start.0 < addr.0 + size
+    }
+
+    /// Check if `size` bytes at `addr` are fully contained in this region
+    pub fn contains(&self, addr: VirtAddr, size: usize) -> bool {
+        addr.0 >= self.start.0 && addr.0 + size <= self.end
+    }
+}
+
+/// Find the region servicing an access of `size` bytes at `addr`. Accesses
+/// which only partially overlap a region are rejected, without a location
+pub fn find(regions: &[MmioRegion], addr: VirtAddr, size: usize)
+        -> Result<Option<&MmioRegion>, VmExit> {
+    for region in regions {
+        if region.overlaps(addr, size) {
+            if !region.contains(addr, size) {
+                return Err(VmExit::AddressMiss(addr, size, None));
+            }
+            return Ok(Some(region));
+        }
+    }
+
+    Ok(None)
+}
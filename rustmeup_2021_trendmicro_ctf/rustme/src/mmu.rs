@@ -3,7 +3,15 @@
 
 use crate::primitive::Primitive;
 use crate::emulator::VmExit;
+use crate::mmio::{self, MmioHandle, MmioRegion};
+use crate::region::{self, Location, Region, RegionKind};
+use crate::taint::TaintLabel;
+use crate::snapshot::{self, read_u64, read_usize, write_u64};
 use std::path::Path;
+use std::io::{self, Read, Write};
+use std::convert::TryFrom;
+use std::cell::Cell;
+use std::fmt;
 
 // Block size used for resetting and tracking memory which has been modified
 /// THe larger this is, the fewer but more expensive memcpys() need to occur,
@@ -11,6 +19,9 @@ use std::path::Path;
 /// It seems the sweet spot is often 128-4096 bytes. 
 const DIRTY_BLOCK_SIZE: usize = 4096;
 
+/// Largest guest memory, every byte of it is backed by host memory
+pub const MAX_MEMORY_SIZE: usize = 4 * 1024 * 1024 * 1024;
+
 
 pub const PERM_READ: u8  = 1 << 0;
 pub const PERM_WRITE: u8 = 1 << 1;
@@ -27,6 +38,60 @@ pub struct Perm(pub u8);
 #[repr(transparent)]
 pub struct VirtAddr(pub usize);
 
+impl fmt::Display for Perm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |bit, c| if (self.0 & bit) != 0 { c } else { '-' };
+        write!(f, "{}{}{}{}", flag(PERM_READ, 'r'), flag(PERM_WRITE, 'w'),
+               flag(PERM_EXEC, 'x'), flag(PERM_RAW, 'u'))
+    }
+}
+
+/// A change of a fork relative to the MMU it was forked from
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Diff {
+    /// The bytes at `addr` changed from `old` to `new`
+    Memory {
+        addr: VirtAddr,
+        old:  Vec<u8>,
+        new:  Vec<u8>,
+    },
+
+    /// The permissions of `size` bytes at `addr` changed from `old` to `new`
+    Permissions {
+        addr: VirtAddr,
+        size: usize,
+        old:  Perm,
+        new:  Perm,
+    },
+}
+
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        /// Only show this many bytes of a change
+        const MAX_BYTES: usize = 32;
+
+        let hex = |bytes: &[u8]| {
+            let mut out: Vec<String> = bytes.iter().take(MAX_BYTES)
+                .map(|x| format!("{:02x}", x)).collect();
+            if bytes.len() > MAX_BYTES {
+                out.push("...".into());
+            }
+            out.join(" ")
+        };
+
+        match self {
+            Diff::Memory { addr, old, new } => {
+                write!(f, "memory {:#x}..{:#x} ({} bytes)\n  old {}\n  new {}",
+                       addr.0, addr.0 + new.len(), new.len(), hex(old), hex(new))
+            }
+            Diff::Permissions { addr, size, old, new } => {
+                write!(f, "perms  {:#x}..{:#x} ({} bytes) {} -> {}",
+                       addr.0, addr.0 + size, size, old, new)
+            }
+        }
+    }
+}
+
 pub struct Sections {
     pub file_offset: usize,
     pub virt_addr: VirtAddr,
@@ -36,6 +101,126 @@ pub struct Sections {
 }
 
 
+/// A range of guest memory which is watched for accesses
+#[derive(Clone, Copy, Debug)]
+struct Watchpoint {
+    /// First address covered by the watchpoint
+    start: VirtAddr,
+
+    /// One past the last address covered by the watchpoint
+    end: usize,
+
+    /// Access kinds which trigger the watchpoint, any of `PERM_READ`,
+    /// `PERM_WRITE` and `PERM_EXEC`
+    kind: Perm,
+}
+
+/// Summary of the permissions of a block, used to skip checking every byte
+/// of an access
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct PermSummary {
+    /// Permission bits set on every byte of the block
+    all: Perm,
+
+    /// Permission bits set on any byte of the block
+    any: Perm,
+
+    /// Number of bytes in the block without `PERM_READ`, so `all` can be
+    /// updated as writes to RAW memory make bytes readable
+    unread: usize,
+}
+
+impl PermSummary {
+    /// Compute the summary of the permissions of a block
+    fn new(perms: &[Perm]) -> Self {
+        let init = PermSummary { all: Perm(!0), any: Perm(0), unread: 0 };
+        perms.iter().fold(init, |acc, x| PermSummary {
+            all:    Perm(acc.all.0 & x.0),
+            any:    Perm(acc.any.0 | x.0),
+            unread: acc.unread + ((x.0 & PERM_READ) == 0) as usize,
+        })
+    }
+}
+
+/// A modification of memory recorded while reset verification is on
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct WriteRecord {
+    /// Address of the first byte modified
+    pub addr: VirtAddr,
+
+    /// Number of bytes modified
+    pub size: usize,
+
+    /// `true` for a permission change, `false` for a write of memory
+    pub permissions: bool,
+
+    /// Guest PC of the instruction which made it, or whose system call did
+    pub pc: VirtAddr,
+}
+
+/// A block which `reset` failed to restore to the state of the parent
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ResetMismatch {
+    /// Index of the block
+    pub block: usize,
+
+    /// First address in the block which differs from the parent
+    pub addr: VirtAddr,
+
+    /// `true` if the permissions differ, `false` if the memory differs
+    pub permissions: bool,
+
+    /// Newest recorded modification touching `addr`, if any
+    pub write: Option<WriteRecord>,
+}
+
+impl fmt::Display for ResetMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "block {} not restored, {} differ at {:#x}", self.block,
+               if self.permissions { "permissions" } else { "memory" },
+               self.addr.0)?;
+
+        match self.write {
+            Some(write) => write!(f, ", last {} of {} bytes at {:#x} by pc {:#x}",
+                                  if write.permissions { "permission change" }
+                                  else { "write" },
+                                  write.size, write.addr.0, write.pc.0),
+            None => write!(f, ", no recorded write"),
+        }
+    }
+}
+
+/// Contents of a block at the time a checkpoint was taken
+struct SavedBlock {
+    /// Index of the block
+    block: usize,
+
+    /// Memory of the block
+    memory: Vec<u8>,
+
+    /// Permissions of the block
+    permissions: Vec<Perm>,
+
+    /// Taint labels of the block, if taint tracking was on
+    taint: Option<Vec<TaintLabel>>,
+}
+
+/// A checkpoint in the stack of checkpoints of an MMU
+struct Checkpoint {
+    /// Blocks modified while this was the newest checkpoint, holding their
+    /// contents from when they were first modified
+    saved: Vec<SavedBlock>,
+
+    /// Tracks which blocks are in `saved`
+    saved_bitmap: Vec<u64>,
+
+    /// Allocator state when the checkpoint was taken
+    cur_alloc: VirtAddr,
+
+    /// Named regions when the checkpoint was taken
+    regions: Vec<Region>,
+}
+
 pub struct Mmu{
     /// Block of memory for this address space
     /// offset 0 correspons to address 0 in the guest address space
@@ -49,9 +234,38 @@ pub struct Mmu{
 
     /// Tracks which parts of memeory have been dirtied
     dirty_bitmap: Vec<u64>,
+
+    /// Permission summary of every block of `DIRTY_BLOCK_SIZE` bytes
+    block_perms: Vec<PermSummary>,
     
     /// current base of address of next allocations
     cur_alloc: VirtAddr,
+
+    /// Registered watchpoints, indexed by the id returned when adding them
+    watchpoints: Vec<Option<Watchpoint>>,
+
+    /// First watchpoint hit since the last `take_watch_hit`, as the watched
+    /// address which was touched and the kind of access
+    watch_hit: Cell<Option<(VirtAddr, Perm)>>,
+
+    /// Regions whose accesses go to host devices rather than `memory`
+    mmio: Vec<MmioRegion>,
+
+    /// Taint label of every byte in `memory`, `None` if taint tracking is off
+    taint: Option<Vec<TaintLabel>>,
+
+    /// Stack of checkpoints taken since the fork, oldest first
+    checkpoints: Vec<Checkpoint>,
+
+    /// Modifications since the last reset, `None` unless reset verification
+    /// is on
+    write_log: Option<Vec<WriteRecord>>,
+
+    /// Guest PC of the instruction being executed, for the write log
+    pc: VirtAddr,
+
+    /// Named regions of memory, used to describe the location of faults
+    regions: Vec<Region>,
 }
 
 impl Mmu{
@@ -63,7 +277,19 @@ impl Mmu{
             permissions:  vec![Perm(0); size],
             dirty:        Vec::with_capacity(size / DIRTY_BLOCK_SIZE + 1),
             dirty_bitmap: vec![0u64; size / DIRTY_BLOCK_SIZE / 64 + 1],
+            block_perms:  (0..size).step_by(DIRTY_BLOCK_SIZE).map(|x| PermSummary {
+                all: Perm(0), any: Perm(0),
+                unread: size.min(x + DIRTY_BLOCK_SIZE) - x,
+            }).collect(),
             cur_alloc:     VirtAddr(0x1000),
+            watchpoints:  Vec::new(),
+            watch_hit:    Cell::new(None),
+            mmio:         Vec::new(),
+            taint:        None,
+            checkpoints:  Vec::new(),
+            write_log:    None,
+            pc:           VirtAddr(0),
+            regions:      Vec::new(),
         }
     }
 
@@ -76,19 +302,29 @@ impl Mmu{
             permissions:  self.permissions.clone(),
             dirty:        Vec::with_capacity(size / DIRTY_BLOCK_SIZE + 1),
             dirty_bitmap: vec![0u64; size / DIRTY_BLOCK_SIZE / 64 + 1],
-            cur_alloc:    self.cur_alloc.clone(),
+            block_perms:  self.block_perms.clone(),
+            cur_alloc:    self.cur_alloc,
+            watchpoints:  self.watchpoints.clone(),
+            watch_hit:    Cell::new(None),
+            mmio:         self.mmio.clone(),
+            taint:        self.taint.clone(),
+            checkpoints:  Vec::new(),
+            write_log:    self.write_log.as_ref().map(|_| Vec::new()),
+            pc:           self.pc,
+            regions:      self.regions.clone(),
         }
     }
 
     
     /// Restore memory back to the original state ( eg. restores all
-    /// dirty block to the state of `other`
-    pub fn reset(&mut self, other: &Mmu){
+    /// dirty block to the state of `other`. With reset verification on,
+    /// returns the blocks which still differ from `other`
+    pub fn reset(&mut self, other: &Mmu) -> Result<(), Vec<ResetMismatch>> {
 
         for &block in &self.dirty {
             //Get the start and end addresses of the dirtied memory
             let start = block * DIRTY_BLOCK_SIZE;
-            let end = (block + 1) * DIRTY_BLOCK_SIZE; 
+            let end = self.memory.len().min((block + 1) * DIRTY_BLOCK_SIZE); 
             
             // Zero the bitmap. 
             self.dirty_bitmap[block / 64] = 0;
@@ -99,6 +335,12 @@ impl Mmu{
             // Restor permissions
             self.permissions[start..end].copy_from_slice(
                 &other.permissions[start..end]);
+            self.block_perms[block] = other.block_perms[block];
+
+            // Restore taint labels
+            if let (Some(taint), Some(other)) = (&mut self.taint, &other.taint) {
+                taint[start..end].copy_from_slice(&other[start..end]);
+            }
         }
 
         //cleart dirty lisrt
@@ -106,11 +348,319 @@ impl Mmu{
 
         //Restore allocator state
         self.cur_alloc = other.cur_alloc;
+        self.regions.clone_from(&other.regions);
+
+        // Checkpoints are all newer than `other`
+        self.checkpoints.clear();
+
+        if self.write_log.is_some() {
+            let mismatches = self.verify_reset(other);
+            self.write_log = Some(Vec::new());
+            if !mismatches.is_empty() {
+                return Err(mismatches);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Turn reset verification on or off. When on, every modification is
+    /// logged and each `reset` compares all of memory and permissions against
+    /// the parent, returning the offending blocks on a mismatch. This is slow
+    /// and meant for debugging dirty tracking
+    pub fn set_reset_verification(&mut self, enabled: bool) {
+        self.write_log = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    /// Compare all of memory and permissions against `other`, returning every
+    /// block which differs. Meant to be called right after `reset(other)`
+    pub fn verify_reset(&self, other: &Mmu) -> Vec<ResetMismatch> {
+        let mut mismatches = Vec::new();
+
+        for (block, start) in (0..self.memory.len())
+                .step_by(DIRTY_BLOCK_SIZE).enumerate() {
+            let end = self.memory.len().min(start + DIRTY_BLOCK_SIZE);
+
+            // Compare whole blocks first, it is much faster
+            if self.memory[start..end] == other.memory[start..end] &&
+                    self.permissions[start..end] == other.permissions[start..end] {
+                continue;
+            }
+
+            let memory = (start..end)
+                .find(|&x| self.memory[x] != other.memory[x])
+                .map(|x| (x, false));
+            let perms = (start..end)
+                .find(|&x| self.permissions[x] != other.permissions[x])
+                .map(|x| (x, true));
+
+            for (addr, permissions) in memory.into_iter().chain(perms) {
+                // Blame the newest modification of the offending byte
+                let write = self.write_log.iter().flatten().rev()
+                    .find(|x| x.permissions == permissions &&
+                          x.addr.0 <= addr && addr < x.addr.0 + x.size)
+                    .copied();
+
+                mismatches.push(ResetMismatch {
+                    block, addr: VirtAddr(addr), permissions, write,
+                });
+            }
+        }
+
+        mismatches
+    }
+
+    /// Set the guest PC of the instruction being executed, which modifications
+    /// are blamed on by reset verification
+    pub fn set_pc(&mut self, pc: VirtAddr) {
+        self.pc = pc;
+    }
+
+    /// Record a modification for reset verification
+    fn log_write(&mut self, addr: VirtAddr, size: usize, permissions: bool) {
+        if let Some(log) = &mut self.write_log {
+            log.push(WriteRecord { addr, size, permissions, pc: self.pc });
+        }
+    }
 
+    /// Take a checkpoint of the current state and return its depth. Memory,
+    /// permissions, taint and the allocator can later be restored to it with
+    /// `restore`
+    pub fn checkpoint(&mut self) -> usize {
+        self.checkpoints.push(Checkpoint {
+            saved:        Vec::new(),
+            saved_bitmap: vec![0u64; self.dirty_bitmap.len()],
+            cur_alloc:    self.cur_alloc,
+            regions:      self.regions.clone(),
+        });
+        self.checkpoints.len() - 1
+    }
+
+    /// Number of checkpoints currently on the stack
+    pub fn checkpoint_depth(&self) -> usize {
+        self.checkpoints.len()
+    }
+
+    /// Restore the state to the checkpoint at `depth`, dropping all newer
+    /// checkpoints. The checkpoint itself is kept so it can be restored again.
+    /// Only blocks modified since the checkpoint are copied
+    pub fn restore(&mut self, depth: usize) -> Option<()> {
+        if depth >= self.checkpoints.len() {
+            return None;
+        }
+
+        // Undo newest first, so the oldest saved copy of a block wins
+        while self.checkpoints.len() > depth {
+            let checkpoint = self.checkpoints.pop().unwrap();
+            for saved in checkpoint.saved {
+                let start = saved.block * DIRTY_BLOCK_SIZE;
+                let end = start + saved.memory.len();
+
+                self.memory[start..end].copy_from_slice(&saved.memory);
+                self.permissions[start..end].copy_from_slice(&saved.permissions);
+                self.block_perms[saved.block] = PermSummary::new(&saved.permissions);
+                if let (Some(taint), Some(saved)) = (&mut self.taint, &saved.taint) {
+                    taint[start..end].copy_from_slice(saved);
+                }
+            }
+            self.cur_alloc = checkpoint.cur_alloc;
+            self.regions = checkpoint.regions;
+        }
+
+        self.checkpoint();
+        Some(())
+    }
+
+    /// Save the blocks covering `size` bytes at `addr` into the newest
+    /// checkpoint before they are modified. The range must already be bounds
+    /// checked
+    fn save_blocks(&mut self, addr: VirtAddr, size: usize) {
+        let checkpoint = match self.checkpoints.last_mut() {
+            Some(checkpoint) if size > 0 => checkpoint,
+            _ => return,
+        };
+
+        let block_start = addr.0 / DIRTY_BLOCK_SIZE;
+        let block_end = (addr.0 + size - 1) / DIRTY_BLOCK_SIZE;
+
+        for block in block_start..=block_end {
+            let idx = block / 64;
+            let bit = block % 64;
+
+            if checkpoint.saved_bitmap[idx] & (1 << bit) == 0 {
+                let start = block * DIRTY_BLOCK_SIZE;
+                let end = self.memory.len().min(start + DIRTY_BLOCK_SIZE);
+
+                checkpoint.saved.push(SavedBlock {
+                    block,
+                    memory:      self.memory[start..end].to_vec(),
+                    permissions: self.permissions[start..end].to_vec(),
+                    taint:       self.taint.as_ref().map(|x| x[start..end].to_vec()),
+                });
+                checkpoint.saved_bitmap[idx] |= 1 << bit;
+            }
+        }
     }
     
-    /// function allocates the address
-    pub fn allocate(&mut self, size: usize) -> Option<VirtAddr>{
+    /// Watch `size` bytes at `addr` for the accesses in `kind` (any of
+    /// `PERM_READ`, `PERM_WRITE` and `PERM_EXEC`). Returns an id which can be
+    /// used to remove the watchpoint
+    pub fn add_watchpoint(&mut self, addr: VirtAddr, size: usize,
+                          kind: Perm) -> Option<usize> {
+        let end = addr.0.checked_add(size)?;
+        if size == 0 || end > self.memory.len() {
+            return None;
+        }
+
+        self.watchpoints.push(Some(Watchpoint { start: addr, end, kind }));
+        Some(self.watchpoints.len() - 1)
+    }
+
+    /// Remove the watchpoint with `id`, returns `false` if there was none
+    pub fn remove_watchpoint(&mut self, id: usize) -> bool {
+        self.watchpoints.get_mut(id).and_then(|x| x.take()).is_some()
+    }
+
+    /// Get and clear the first watchpoint hit since the last call
+    pub fn take_watch_hit(&self) -> Option<(VirtAddr, Perm)> {
+        self.watch_hit.take()
+    }
+
+    /// Record a hit if an access of `kind` to `size` bytes at `addr` touches
+    /// any watchpoint. Only the first hit is kept until it is taken
+    fn check_watch(&self, addr: VirtAddr, size: usize, kind: Perm) {
+        if self.watchpoints.is_empty() || self.watch_hit.get().is_some() {
+            return;
+        }
+
+        let end = addr.0 + size;
+        for wp in self.watchpoints.iter().flatten() {
+            if (wp.kind.0 & kind.0) != 0 && addr.0 < wp.end && wp.start.0 < end {
+                self.watch_hit.set(
+                    Some((VirtAddr(addr.0.max(wp.start.0)), kind)));
+                return;
+            }
+        }
+    }
+
+    /// Start tracking taint, all memory starts out clean
+    pub fn enable_taint(&mut self) {
+        if self.taint.is_none() {
+            self.taint = Some(vec![TaintLabel::CLEAN; self.memory.len()]);
+        }
+    }
+
+    /// Get the taint labels of `size` bytes at `addr`. Returns `None` if taint
+    /// tracking is off or the range is out of bounds
+    pub fn taint(&self, addr: VirtAddr, size: usize) -> Option<&[TaintLabel]> {
+        self.taint.as_ref()?.get(addr.0..addr.0.checked_add(size)?)
+    }
+
+    /// Label `size` bytes at `addr` with `label`. Labels are undone by
+    /// `reset` and `restore` like writes
+    pub fn set_taint(&mut self, addr: VirtAddr, size: usize,
+                     label: TaintLabel) -> Option<()> {
+        let end = addr.0.checked_add(size)?;
+        if self.taint.as_ref()?.len() < end {
+            return None;
+        }
+
+        self.save_blocks(addr, size);
+        self.taint.as_mut()?[addr.0..end].iter_mut().for_each(|x| *x = label);
+        self.mark_dirty(addr, size);
+
+        Some(())
+    }
+
+    /// Route accesses to `size` bytes at `addr` to `device` instead of
+    /// backing memory. The region is given `perm`, which accesses must still
+    /// satisfy. Accesses only partly inside the region, such as ones
+    /// straddling it and memory, fail with `VmExit::AddressMiss` and reach
+    /// neither. Fails if the range is out of bounds or overlaps another region
+    pub fn map_mmio(&mut self, addr: VirtAddr, size: usize, perm: Perm,
+                    device: MmioHandle) -> Option<()> {
+        let end = addr.0.checked_add(size)?;
+        if size == 0 || end > self.memory.len() ||
+                self.mmio.iter().any(|x| x.overlaps(addr, size)) {
+            return None;
+        }
+
+        self.set_permissions(addr, size, perm)?;
+        self.mmio.push(MmioRegion { start: addr, end, device });
+        Some(())
+    }
+
+    /// Get the permissions of the byte at `addr`, `None` if out of bounds
+    pub fn perms(&self, addr: VirtAddr) -> Option<Perm> {
+        self.permissions.get(addr.0).copied()
+    }
+
+    /// Name `size` bytes at `addr` as a region of `kind`. A region directly
+    /// following the newest region of the same kind extends it. Fails if the
+    /// range is empty or out of bounds
+    pub fn add_region(&mut self, kind: RegionKind, addr: VirtAddr,
+                      size: usize) -> Option<()> {
+        let end = addr.0.checked_add(size)?;
+        if size == 0 || end > self.memory.len() {
+            return None;
+        }
+
+        match self.regions.last_mut() {
+            Some(last) if last.kind == kind && last.end == addr.0 => {
+                last.end = end;
+            }
+            _ => self.regions.push(Region { kind, start: addr, end }),
+        }
+        Some(())
+    }
+
+    /// Named regions of memory, oldest first
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    /// Describe where `addr` lies relative to the named regions
+    pub fn locate(&self, addr: VirtAddr) -> Option<Location> {
+        region::locate(&self.regions, addr)
+    }
+
+    /// Build the exit for a fault at `addr` with `exit`, unless the fault hit
+    /// a stack guard which makes it a stack overflow
+    fn fault(&self, addr: VirtAddr,
+             exit: impl FnOnce(Option<Location>) -> VmExit) -> VmExit {
+        match self.locate(addr) {
+            Some(Location::Inside { region, .. })
+                    if region.kind == RegionKind::Guard => {
+                VmExit::StackOverflow(addr)
+            }
+            loc => exit(loc),
+        }
+    }
+
+    /// Build a read fault at `addr`
+    fn read_fault(&self, addr: VirtAddr) -> VmExit {
+        self.fault(addr, |loc| VmExit::ReadFault(addr, loc))
+    }
+
+    /// Build a write fault at `addr`
+    fn write_fault(&self, addr: VirtAddr) -> VmExit {
+        self.fault(addr, |loc| VmExit::WriteFault(addr, loc))
+    }
+
+    /// Build an address miss for `size` bytes at `addr`
+    fn address_miss(&self, addr: VirtAddr, size: usize) -> VmExit {
+        self.fault(addr, |loc| VmExit::AddressMiss(addr, size, loc))
+    }
+
+    /// Address the next allocation will be placed at
+    pub fn next_alloc(&self) -> VirtAddr {
+        self.cur_alloc
+    }
+
+    /// Allocate `size` bytes of memory as a region of `kind`. The allocator
+    /// is left alone if the memory runs out
+    pub fn allocate(&mut self, size: usize, kind: RegionKind)
+            -> Option<VirtAddr>{
         
         // Align the address size
         let align_size = size; // + 0xF & !0xF; 
@@ -118,77 +668,242 @@ impl Mmu{
         let base = self.cur_alloc;
         
         // Cannot allocate
-        if base.0 >= self.memory.len() {
+        let end = base.0.checked_add(align_size)?;
+        if end > self.memory.len() {
             return None;
         }
 
-        self.cur_alloc = VirtAddr(self.cur_alloc.0.checked_add(align_size)?);
-
-        if self.memory.len() < self.cur_alloc.0 {
-             return None;
-        }
+        self.cur_alloc = VirtAddr(end);
 
         self.set_permissions(base, align_size, Perm(PERM_RAW|PERM_WRITE));
+        if align_size > 0 {
+            self.add_region(kind, base, align_size)?;
+        }
 
         Some(base)
 
 
     }
+    /// Allocate a stack of `size` bytes above `guard` bytes of unmapped
+    /// memory. Accesses to the guard exit with `VmExit::StackOverflow`
+    pub fn allocate_stack(&mut self, size: usize, guard: usize)
+            -> Option<VirtAddr> {
+        let guard_base = self.allocate(guard, RegionKind::Guard)?;
+        self.set_permissions(guard_base, guard, Perm(0))?;
+        self.allocate(size, RegionKind::Stack)
+    }
+
         /// Apply permissions to a region of memory
         pub fn set_permissions(&mut self, addr: VirtAddr, size: 
                                usize, perm: Perm) -> Option<()> {
-            self.permissions.get_mut(addr.0..addr.0.checked_add(size)?)?
-                .iter_mut().for_each(|x| *x = perm);
+            let end = addr.0.checked_add(size)?;
+            if end > self.permissions.len() {
+                return None;
+            }
+
+            self.save_blocks(addr, size);
+            self.log_write(addr, size, true);
+            self.permissions[addr.0..end].iter_mut().for_each(|x| *x = perm);
+            self.update_block_perms(addr, size);
+
+            // Permission changes must be undone by `reset` too
+            self.mark_dirty(addr, size);
 
             Some(())
         }
 
-        pub fn write_from(&mut self, addr: VirtAddr,  buf: &[u8]) 
-            -> Result<(), VmExit> {
+        /// Recompute the permission summaries of the blocks covering `size`
+        /// bytes at `addr`. The range must already be bounds checked
+        fn update_block_perms(&mut self, addr: VirtAddr, size: usize) {
+            if size == 0 {
+                return;
+            }
 
-            let perms = self.permissions.get_mut(addr.0..addr.0.checked_add(buf.len())
-                                                 .ok_or(VmExit::AddressIntegerOverflow)?)
-                .ok_or(VmExit::AddressMiss(addr, buf.len()))?;
-             
-            let mut has_raw = false;
-            
-            for (idx, &perm) in perms.iter().enumerate() {
-                has_raw |= (perm.0 & PERM_RAW)  != 0;
-                if (perm.0 & PERM_WRITE) == 0 {
-                    return Err(VmExit::WriteFault(VirtAddr(addr.0 + idx)));
-                }
+            let block_start = addr.0 / DIRTY_BLOCK_SIZE;
+            let block_end = (addr.0 + size - 1) / DIRTY_BLOCK_SIZE;
+
+            for block in block_start..=block_end {
+                let start = block * DIRTY_BLOCK_SIZE;
+                let end = self.permissions.len().min(start + DIRTY_BLOCK_SIZE);
+                self.block_perms[block] = PermSummary::new(&self.permissions[start..end]);
+            }
+        }
+
+        /// Check using the block summaries if every byte of `size` bytes at
+        /// `addr` has all of `exp_perms`. The access must be in bounds and in
+        /// a single block, otherwise this conservatively returns `false`
+        fn fast_perms(&self, addr: VirtAddr, size: usize, exp_perms: u8) -> bool {
+            let block = addr.0 / DIRTY_BLOCK_SIZE;
+            size != 0 &&
+                addr.0.checked_add(size).is_some_and(|x| x <= self.memory.len()) &&
+                (addr.0 + size - 1) / DIRTY_BLOCK_SIZE == block &&
+                (self.block_perms[block].all.0 & exp_perms) == exp_perms
+        }
+
+        /// Check using the block summaries if a write of `size` bytes at
+        /// `addr` is allowed and has no RAW bytes left to mark readable
+        fn fast_write(&self, addr: VirtAddr, size: usize) -> bool {
+            self.fast_perms(addr, size, PERM_WRITE) && {
+                let summary = self.block_perms[addr.0 / DIRTY_BLOCK_SIZE];
+                (summary.any.0 & PERM_RAW) == 0 || (summary.all.0 & PERM_READ) != 0
+            }
+        }
+
+        /// Check if a read of `size` bytes at `addr` can go straight to
+        /// memory, skipping the byte by byte checks
+        fn read_fast_path(&self, addr: VirtAddr, size: usize, exp_perms: u8) -> bool {
+            self.watchpoints.is_empty() && self.mmio.is_empty() &&
+                self.fast_perms(addr, size, exp_perms)
+        }
+
+        /// Check if a write of `size` bytes at `addr` can go straight to
+        /// memory, with nothing but the dirty list needing to see it
+        fn write_fast_path(&self, addr: VirtAddr, size: usize) -> bool {
+            self.watchpoints.is_empty() && self.mmio.is_empty() &&
+                self.taint.is_none() && self.checkpoints.is_empty() &&
+                self.write_log.is_none() && self.fast_write(addr, size)
+        }
+
+        /// Add the blocks covering `size` bytes at `addr` to the dirty list.
+        /// The range must already be bounds checked
+        fn mark_dirty(&mut self, addr: VirtAddr, size: usize) {
+            if size == 0 {
+                return;
             }
-           
-            // Copy the buffer into memory
-            self.memory[addr.0..addr.0 + buf.len()].copy_from_slice(buf);
 
             // Compute dirty bit blocks
             let block_start = addr.0 / DIRTY_BLOCK_SIZE;
-            let block_end = (addr.0 + buf.len()) / DIRTY_BLOCK_SIZE;
+            let block_end = (addr.0 + size - 1) / DIRTY_BLOCK_SIZE;
              
             for block in block_start..=block_end{
                 // Determine the bitmap postion of the dirty block
-                let idx = block_start / 64;
-                let bit = block_start  % 64;
+                let idx = block / 64;
+                let bit = block % 64;
                 
                 // Check if the block is not dirty
                 if self.dirty_bitmap[idx] & (1 << bit) == 0 {
                     // Block is not dirty add it to the dirty list
-                    self.dirty.push(block );
+                    self.dirty.push(block);
 
                     // Update the dirty bitmap
-                    self.dirty_bitmap[idx]  |= 1 << bit; 
-                }}
+                    self.dirty_bitmap[idx] |= 1 << bit; 
+                }
+            }
+        }
+
+        /// List the memory and permission changes of `self` relative to
+        /// `parent`, assuming `self` was forked off of `parent`. Only dirty
+        /// blocks are compared, results are sorted by address
+        pub fn diff(&self, parent: &Mmu) -> Vec<Diff> {
+            let mut blocks = self.dirty.clone();
+            blocks.sort_unstable();
+
+            let mut memory = Vec::new();
+            let mut perms  = Vec::new();
+            for block in blocks {
+                let start = block * DIRTY_BLOCK_SIZE;
+                let end = self.memory.len().min(start + DIRTY_BLOCK_SIZE);
+
+                for addr in start..end {
+                    // Extend the previous change if it ends right here
+                    let (old, new) = (parent.memory[addr], self.memory[addr]);
+                    if old != new {
+                        match memory.last_mut() {
+                            Some(Diff::Memory { addr: base, old: olds, new: news })
+                                    if base.0 + olds.len() == addr => {
+                                olds.push(old);
+                                news.push(new);
+                            }
+                            _ => memory.push(Diff::Memory {
+                                addr: VirtAddr(addr), old: vec![old], new: vec![new],
+                            }),
+                        }
+                    }
+
+                    let (old, new) = (parent.permissions[addr], self.permissions[addr]);
+                    if old != new {
+                        match perms.last_mut() {
+                            Some(Diff::Permissions { addr: base, size, old: o, new: n })
+                                    if base.0 + *size == addr && *o == old && *n == new => {
+                                *size += 1;
+                            }
+                            _ => perms.push(Diff::Permissions {
+                                addr: VirtAddr(addr), size: 1, old, new,
+                            }),
+                        }
+                    }
+                }
+            }
+
+            memory.append(&mut perms);
+            memory.sort_by_key(|x| match x {
+                Diff::Memory { addr, .. } | Diff::Permissions { addr, .. } => *addr,
+            });
+            memory
+        }
+
+        pub fn write_from(&mut self, addr: VirtAddr,  buf: &[u8]) 
+            -> Result<(), VmExit> {
+
+            let mut has_raw = false;
+
+            // Skip checking every byte if the block summary allows the write
+            if !self.fast_write(addr, buf.len()) {
+                let perms = self.permissions.get(addr.0..addr.0.checked_add(buf.len())
+                                                     .ok_or(VmExit::AddressIntegerOverflow)?)
+                    .ok_or_else(|| self.address_miss(addr, buf.len()))?;
+            
+                for (idx, &perm) in perms.iter().enumerate() {
+                    has_raw |= (perm.0 & PERM_RAW)  != 0;
+                    if (perm.0 & PERM_WRITE) == 0 {
+                        return Err(self.write_fault(VirtAddr(addr.0 + idx)));
+                    }
+                }
+            }
+
+            // Device writes bypass memory, dirty tracking and RAW promotion
+            if let Some(region) = mmio::find(&self.mmio, addr, buf.len())
+                    .map_err(|_| self.address_miss(addr, buf.len()))? {
+                region.device.lock().unwrap()
+                    .write(addr.0 - region.start.0, buf)?;
+                self.check_watch(addr, buf.len(), Perm(PERM_WRITE));
+                return Ok(());
+            }
+           
+            self.save_blocks(addr, buf.len());
+            self.log_write(addr, buf.len(), false);
+
+            // Copy the buffer into memory
+            self.memory[addr.0..addr.0 + buf.len()].copy_from_slice(buf);
+
+            // Fresh bytes are clean, stores label them after the write
+            if let Some(taint) = &mut self.taint {
+                taint[addr.0..addr.0 + buf.len()].iter_mut()
+                    .for_each(|x| *x = TaintLabel::CLEAN);
+            }
 
             if has_raw {
-                perms.iter_mut().for_each(|x| {
-                    if (x.0 & PERM_RAW) != 0 {
+                self.log_write(addr, buf.len(), true);
+                for idx in addr.0..addr.0 + buf.len() {
+                    let perm = self.permissions[idx];
+                    if (perm.0 & PERM_RAW) != 0 && (perm.0 & PERM_READ) == 0 {
                         //mark the memory readable
-                        *x = Perm(x.0 | PERM_READ);
+                        self.permissions[idx] = Perm(perm.0 | PERM_READ);
+
+                        // Keep the block summary exact
+                        let summary = &mut self.block_perms[idx / DIRTY_BLOCK_SIZE];
+                        summary.any = Perm(summary.any.0 | PERM_READ);
+                        summary.unread -= 1;
+                        if summary.unread == 0 {
+                            summary.all = Perm(summary.all.0 | PERM_READ);
+                        }
                     }
-                });
+                }
             }
 
+            self.mark_dirty(addr, buf.len());
+
+            self.check_watch(addr, buf.len(), Perm(PERM_WRITE));
 
             Ok(())
         }
@@ -200,13 +915,20 @@ impl Mmu{
 
             let perms = self.permissions.get(addr.0..addr.0.checked_add(size)
                                                  .ok_or(VmExit::AddressIntegerOverflow)?)
-                .ok_or(VmExit::AddressMiss(addr, size))?;
+                .ok_or_else(|| self.address_miss(addr, size))?;
             
             for (idx, &perm) in perms.iter().enumerate() {
                 if (perm.0 & exp_perms.0) != exp_perms.0 {
-                    return Err(VmExit::ReadFault(VirtAddr(addr.0 + idx)));
+                    return Err(self.read_fault(VirtAddr(addr.0 + idx)));
                 }
             }
+
+            // Device memory has no backing bytes to hand out
+            if let Some(region) = self.mmio.iter().find(|x| x.overlaps(addr, size)) {
+                return Err(self.read_fault(VirtAddr(addr.0.max(region.start.0))));
+            }
+
+            self.check_watch(addr, size, Perm(PERM_READ));
             
             Ok(&self.memory[addr.0..addr.0 + size] )
 
@@ -218,15 +940,32 @@ impl Mmu{
         pub fn read_into_perms(&self, addr: VirtAddr,  buf: &mut [u8], 
                                exp_perms: Perm) -> Result<(), VmExit> {
 
-            let perms = self.permissions.get(addr.0..addr.0.checked_add(buf.len())
-                                                 .ok_or(VmExit::AddressIntegerOverflow)?)
-                .ok_or(VmExit::AddressMiss(addr, buf.len()))?;
+            // Skip checking every byte if the block summary allows the read
+            if !self.fast_perms(addr, buf.len(), exp_perms.0) {
+                let perms = self.permissions.get(addr.0..addr.0.checked_add(buf.len())
+                                                     .ok_or(VmExit::AddressIntegerOverflow)?)
+                    .ok_or_else(|| self.address_miss(addr, buf.len()))?;
             
-            for (idx, &perm) in perms.iter().enumerate() {
-                if (perm.0 & exp_perms.0) != exp_perms.0 {
-                    return Err(VmExit::ReadFault(VirtAddr(addr.0 + idx)));
+                for (idx, &perm) in perms.iter().enumerate() {
+                    if (perm.0 & exp_perms.0) != exp_perms.0 {
+                        return Err(self.read_fault(VirtAddr(addr.0 + idx)));
+                    }
                 }
             }
+
+            // Instruction fetches are reported as executes, all else as reads
+            let kind = if (exp_perms.0 & PERM_EXEC) != 0 {
+                Perm(PERM_EXEC)
+            } else {
+                Perm(PERM_READ)
+            };
+            self.check_watch(addr, buf.len(), kind);
+
+            if let Some(region) = mmio::find(&self.mmio, addr, buf.len())
+                    .map_err(|_| self.address_miss(addr, buf.len()))? {
+                return region.device.lock().unwrap()
+                    .read(addr.0 - region.start.0, buf);
+            }
             
             buf.copy_from_slice(&self.memory[addr.0..addr.0 + buf.len()]);
 
@@ -241,6 +980,16 @@ impl Mmu{
         /// Read a type `T` at `addr` 
         pub fn read_perms<T: Primitive>(&mut self, addr: VirtAddr,
                                         exp_perms: Perm) -> Result<T, VmExit>{
+            let size = core::mem::size_of::<T>();
+
+            // Read straight out of memory when nothing needs to see the access
+            if self.read_fast_path(addr, size, exp_perms.0) {
+                return Ok(unsafe {
+                    core::ptr::read_unaligned(
+                        self.memory.as_ptr().add(addr.0) as *const T)
+                });
+            }
+
             let mut tmp = [0u8; 16];
             self.read_into_perms(addr, &mut tmp[..core::mem::size_of::<T>()], exp_perms)?;
             Ok(unsafe { core::ptr::read_unaligned(tmp.as_ptr() as *const T)})
@@ -257,6 +1006,18 @@ impl Mmu{
         /// Write a type `T` at `addr` 
         pub fn write<T: Primitive>(&mut self, addr: VirtAddr, 
                                    val: T) -> Result<(), VmExit>{
+            let size = core::mem::size_of::<T>();
+
+            // Write straight into memory when nothing needs to see the access
+            if self.write_fast_path(addr, size) {
+                unsafe {
+                    core::ptr::write_unaligned(
+                        self.memory.as_mut_ptr().add(addr.0) as *mut T, val);
+                }
+                self.mark_dirty(addr, size);
+                return Ok(());
+            }
+
             let tmp  = unsafe {
                 core::slice::from_raw_parts(&val as *const T as *const u8,
                                             core::mem::size_of::<T>())
@@ -276,6 +1037,13 @@ impl Mmu{
         // Read the input file
         let contents = std::fs::read(filename).ok()?;
 
+        self.load_image(&contents, sections)
+    }
+
+    /// Load the sections of `contents` into the address space. The allocator
+    /// is moved past the loaded image, making its end the initial break
+    pub fn load_image(&mut self, contents: &[u8],
+                      sections: &[Sections]) -> Option<()> {
         for section in sections {
             self.set_permissions(section.virt_addr, 
                                         section.mem_size,
@@ -288,14 +1056,33 @@ impl Mmu{
                                        section.file_offset.checked_add(section.file_size)?)?
                                    ).ok()?;
 
+            // Name the section, code is text and everything else data
+            let kind = if (section.permissions.0 & PERM_EXEC) != 0 {
+                RegionKind::Text
+            } else {
+                RegionKind::Data
+            };
+            if section.file_size > 0 {
+                self.add_region(kind, section.virt_addr, section.file_size)?;
+            }
+
             // Write in any padding with zeros
             if section.mem_size > section.file_size {
+                self.add_region(RegionKind::Bss,
+                    VirtAddr(section.virt_addr.0 + section.file_size),
+                    section.mem_size - section.file_size)?;
+
                 let padding = vec![0u8; section.mem_size - section.file_size];
                 self.write_from( 
                     VirtAddr(section.virt_addr.0.checked_add(section.file_size)?),
                     &padding).ok()?;
             }
 
+            // Allocate after the loaded image, so allocations never overlap it
+            let end = section.virt_addr.0.checked_add(section.mem_size)?;
+            let end = end.checked_add(0xfff)? & !0xfff;
+            self.cur_alloc = VirtAddr(self.cur_alloc.0.max(end));
+
             // Demote the permission
             for section in sections {
             self.set_permissions(section.virt_addr, 
@@ -308,8 +1095,516 @@ impl Mmu{
 
     }
 
+    /// Serialize the memory, permissions, allocator, dirty state and named
+    /// regions into a snapshot. Blocks which are entirely zero and without
+    /// permissions are skipped. Watchpoints, MMIO regions and taint are host
+    /// side state and are not saved
+    pub fn save_snapshot<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_u64(w, self.memory.len() as u64)?;
+        write_u64(w, self.cur_alloc.0 as u64)?;
+
+        let blocks = self.memory.chunks(DIRTY_BLOCK_SIZE)
+            .zip(self.permissions.chunks(DIRTY_BLOCK_SIZE))
+            .enumerate()
+            .filter(|(_, (mem, perms))| {
+                mem.iter().any(|&x| x != 0) || perms.iter().any(|&x| x.0 != 0)
+            });
+
+        for (block, (mem, perms)) in blocks {
+            write_u64(w, block as u64)?;
+            w.write_all(mem)?;
+            w.write_all(&perms.iter().map(|x| x.0).collect::<Vec<u8>>())?;
+        }
+
+        // End of blocks marker
+        write_u64(w, !0)?;
+
+        write_u64(w, self.dirty.len() as u64)?;
+        for &block in &self.dirty {
+            write_u64(w, block as u64)?;
+        }
+
+        write_u64(w, self.dirty_bitmap.len() as u64)?;
+        for &bits in &self.dirty_bitmap {
+            write_u64(w, bits)?;
+        }
+
+        write_u64(w, self.regions.len() as u64)?;
+        for region in &self.regions {
+            let kind = RegionKind::ALL.iter()
+                .position(|&x| x == region.kind).unwrap();
+            write_u64(w, kind as u64)?;
+            write_u64(w, region.start.0 as u64)?;
+            write_u64(w, region.end as u64)?;
+        }
+
+        Ok(())
+    }
+
+    /// Create an MMU from a snapshot written by `save_snapshot`
+    pub fn load_snapshot<R: Read>(r: &mut R) -> io::Result<Self> {
+        let size = read_usize(r)?;
+        if size > MAX_MEMORY_SIZE {
+            return Err(snapshot::invalid("memory size too large"));
+        }
+        let mut mmu = Mmu::new(size);
+        mmu.cur_alloc = VirtAddr(read_usize(r)?);
+
+        loop {
+            let block = read_u64(r)?;
+            if block == !0 {
+                break;
+            }
+
+            let start = usize::try_from(block).ok()
+                .and_then(|x| x.checked_mul(DIRTY_BLOCK_SIZE))
+                .filter(|&x| x < size)
+                .ok_or_else(|| snapshot::invalid("block out of bounds"))?;
+            let end = size.min(start + DIRTY_BLOCK_SIZE);
+
+            r.read_exact(&mut mmu.memory[start..end])?;
+
+            let mut perms = vec![0u8; end - start];
+            r.read_exact(&mut perms)?;
+            mmu.permissions[start..end].iter_mut().zip(perms)
+                .for_each(|(x, perm)| *x = Perm(perm));
+        }
+
+        let dirty = read_usize(r)?;
+        for _ in 0..dirty {
+            let block = read_usize(r)?;
+            if block.checked_mul(DIRTY_BLOCK_SIZE).is_none_or(|x| x >= size) {
+                return Err(snapshot::invalid("dirty block out of bounds"));
+            }
+            mmu.dirty.push(block);
+        }
+
+        if read_usize(r)? != mmu.dirty_bitmap.len() {
+            return Err(snapshot::invalid("dirty bitmap size mismatch"));
+        }
+        for bits in mmu.dirty_bitmap.iter_mut() {
+            *bits = read_u64(r)?;
+        }
+
+        let regions = read_usize(r)?;
+        for _ in 0..regions {
+            let kind = *RegionKind::ALL.get(read_usize(r)?)
+                .ok_or_else(|| snapshot::invalid("unknown region kind"))?;
+            let start = read_usize(r)?;
+            let end = read_usize(r)?;
+            if start >= end || end > size {
+                return Err(snapshot::invalid("region out of bounds"));
+            }
+            mmu.regions.push(Region { kind, start: VirtAddr(start), end });
+        }
+
+        mmu.update_block_perms(VirtAddr(0), size);
+
+        Ok(mmu)
+    }
+
 
         
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use crate::mmio::MmioDevice;
+    use std::sync::{Arc, Mutex};
+
+    /// Size of the test MMUs, an odd number of bytes so the last block is
+    /// partial
+    const SIZE: usize = 16 * DIRTY_BLOCK_SIZE + 123;
+
+    /// An MMU with all memory writable and every other block readable too,
+    /// and some uninitialized memory
+    fn parent() -> Mmu {
+        let mut mmu = Mmu::new(SIZE);
+        mmu.set_permissions(VirtAddr(0), SIZE, Perm(PERM_WRITE)).unwrap();
+        for block in (0..SIZE / DIRTY_BLOCK_SIZE).step_by(2) {
+            mmu.set_permissions(VirtAddr(block * DIRTY_BLOCK_SIZE),
+                DIRTY_BLOCK_SIZE, Perm(PERM_READ | PERM_WRITE)).unwrap();
+        }
+        mmu.set_permissions(VirtAddr(3 * DIRTY_BLOCK_SIZE + 17), 5000,
+                            Perm(PERM_RAW | PERM_WRITE)).unwrap();
+        mmu.write_from(VirtAddr(100), b"parent contents").unwrap();
+        mmu
+    }
+
+    /// A random range of up to three blocks inside the test MMU
+    fn random_range(rng: &mut StdRng) -> (VirtAddr, usize) {
+        let size = rng.gen_range(1..=3 * DIRTY_BLOCK_SIZE);
+        (VirtAddr(rng.gen_range(0..=SIZE - size)), size)
+    }
+
+    #[test]
+    fn reset_undoes_random_multi_block_writes() {
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        let parent = parent();
+        let mut child = parent.fork();
+        child.set_reset_verification(true);
+
+        for _ in 0..200 {
+            for _ in 0..rng.gen_range(1..20) {
+                let (addr, size) = random_range(&mut rng);
+                child.set_pc(VirtAddr(rng.gen()));
+                if rng.gen_bool(0.2) {
+                    child.set_permissions(addr, size,
+                                          Perm(rng.gen::<u8>() & 0xf)).unwrap();
+                } else {
+                    let data: Vec<u8> = (0..size).map(|_| rng.gen()).collect();
+                    // Writes fail on memory made read-only above, like guest
+                    // stores would
+                    let _ = child.write_from(addr, &data);
+                }
+            }
+
+            // Fails with the unrestored blocks if dirty tracking missed any
+            assert_eq!(child.reset(&parent), Ok(()));
+            assert!(child.memory == parent.memory);
+            assert!(child.permissions == parent.permissions);
+        }
+    }
+
+    #[test]
+    fn reset_verification_blames_the_write() {
+        let parent = parent();
+        let mut child = parent.fork();
+        child.set_reset_verification(true);
+
+        let addr = VirtAddr(5 * DIRTY_BLOCK_SIZE - 2);
+        child.set_pc(VirtAddr(0x1234));
+        child.write_from(VirtAddr(0), b"first").unwrap();
+        child.set_pc(VirtAddr(0x5678));
+        child.write_from(addr, b"span").unwrap();
+        child.set_pc(VirtAddr(0x9abc));
+        child.write_from(VirtAddr(0), b"F").unwrap();
+
+        // Forget the dirty blocks, as if dirty tracking missed them
+        child.dirty.clear();
+        child.dirty_bitmap.iter_mut().for_each(|x| *x = 0);
+
+        let write = |addr, size, pc| Some(WriteRecord {
+            addr: VirtAddr(addr), size, permissions: false, pc: VirtAddr(pc),
+        });
+        let mismatches = child.reset(&parent).unwrap_err();
+        assert_eq!(mismatches, vec![
+            ResetMismatch {
+                block: 0, addr: VirtAddr(0), permissions: false,
+                write: write(0, 1, 0x9abc),
+            },
+            ResetMismatch {
+                block: 4, addr, permissions: false,
+                write: write(addr.0, 4, 0x5678),
+            },
+            ResetMismatch {
+                block: 5, addr: VirtAddr(5 * DIRTY_BLOCK_SIZE), permissions: false,
+                write: write(addr.0, 4, 0x5678),
+            },
+        ]);
+        assert!(mismatches[1].to_string()
+                .ends_with("last write of 4 bytes at 0x4ffe by pc 0x5678"));
+
+        // The log starts afresh, so the next mismatch has no write to blame
+        let mismatches = child.reset(&parent).unwrap_err();
+        assert_eq!(mismatches.len(), 3);
+        assert!(mismatches.iter().all(|x| x.write.is_none()));
+    }
+
+    /// Modify `mmu` with random writes and permission changes
+    fn random_ops(mmu: &mut Mmu, rng: &mut StdRng) {
+        for _ in 0..rng.gen_range(1..20) {
+            let (addr, size) = random_range(rng);
+            if rng.gen_bool(0.3) {
+                mmu.set_permissions(addr, size, Perm(rng.gen::<u8>() & 0xf))
+                    .unwrap();
+            } else {
+                let data: Vec<u8> = (0..size).map(|_| rng.gen()).collect();
+                let _ = mmu.write_from(addr, &data);
+            }
+        }
+    }
+
+    /// Check every block summary matches the permissions it summarizes
+    fn assert_summaries(mmu: &Mmu) {
+        for (block, perms) in mmu.permissions.chunks(DIRTY_BLOCK_SIZE).enumerate() {
+            assert_eq!(mmu.block_perms[block], PermSummary::new(perms),
+                       "stale summary of block {}", block);
+        }
+    }
+
+    #[test]
+    fn summaries_follow_raw_promotion() {
+        let mut mmu = Mmu::new(SIZE);
+        let block = VirtAddr(2 * DIRTY_BLOCK_SIZE);
+        mmu.set_permissions(block, DIRTY_BLOCK_SIZE, Perm(PERM_RAW | PERM_WRITE))
+            .unwrap();
+        assert!(!mmu.fast_perms(block, 8, PERM_READ));
+
+        // Only a fully initialized block may skip the read checks
+        mmu.write_from(block, &[1; DIRTY_BLOCK_SIZE - 1]).unwrap();
+        assert_summaries(&mmu);
+        assert!(!mmu.fast_perms(block, 8, PERM_READ));
+        assert!(mmu.read::<u8>(VirtAddr(block.0 + DIRTY_BLOCK_SIZE - 1)).is_err());
+
+        mmu.write::<u8>(VirtAddr(block.0 + DIRTY_BLOCK_SIZE - 1), 2).unwrap();
+        assert_summaries(&mmu);
+        assert!(mmu.fast_perms(block, 8, PERM_READ));
+        assert!(mmu.fast_write(block, 8));
+        assert_eq!(mmu.read::<u8>(VirtAddr(block.0 + DIRTY_BLOCK_SIZE - 1)), Ok(2));
+    }
+
+    #[test]
+    fn summaries_follow_set_permissions() {
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        let mut mmu = parent();
+        for _ in 0..50 {
+            random_ops(&mut mmu, &mut rng);
+            assert_summaries(&mmu);
+        }
+
+        // Taking a permission away is seen by the next access
+        let addr = VirtAddr(4 * DIRTY_BLOCK_SIZE + 8);
+        mmu.set_permissions(VirtAddr(4 * DIRTY_BLOCK_SIZE), DIRTY_BLOCK_SIZE,
+                            Perm(PERM_READ | PERM_WRITE)).unwrap();
+        assert!(mmu.read::<u64>(addr).is_ok());
+        mmu.set_permissions(VirtAddr(addr.0 + 100), 1, Perm(PERM_READ)).unwrap();
+        assert!(mmu.write::<u64>(addr, 0).is_ok());
+        assert!(mmu.write::<u64>(VirtAddr(addr.0 + 96), 0).is_err());
+        mmu.set_permissions(addr, 1, Perm(PERM_WRITE)).unwrap();
+        assert!(mmu.read::<u64>(addr).is_err());
+    }
+
+    #[test]
+    fn summaries_follow_restore() {
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        let mut mmu = parent();
+        let before = mmu.block_perms.clone();
+
+        let outer = mmu.checkpoint();
+        for _ in 0..20 {
+            random_ops(&mut mmu, &mut rng);
+            mmu.checkpoint();
+            random_ops(&mut mmu, &mut rng);
+            mmu.restore(mmu.checkpoint_depth() - 1).unwrap();
+            assert_summaries(&mmu);
+        }
+        mmu.restore(outer).unwrap();
+        assert_summaries(&mmu);
+        assert!(mmu.block_perms == before);
+    }
+
+    #[test]
+    fn summaries_follow_reset() {
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        let parent = parent();
+        let mut child = parent.fork();
+        for _ in 0..20 {
+            random_ops(&mut child, &mut rng);
+            assert_summaries(&child);
+            child.reset(&parent).unwrap();
+            assert_summaries(&child);
+        }
+    }
+
+    #[test]
+    fn summaries_follow_load_snapshot() {
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        let mut mmu = parent();
+        random_ops(&mut mmu, &mut rng);
+
+        let mut file = Vec::new();
+        mmu.save_snapshot(&mut file).unwrap();
+        let loaded = Mmu::load_snapshot(&mut &file[..]).unwrap();
+        assert_summaries(&loaded);
+        assert!(loaded.block_perms == mmu.block_perms);
+    }
+
+    #[test]
+    fn plain_accesses_take_the_fast_paths() {
+        // Block 0 is uniform, block 1 has a single byte taking it off the
+        // fast paths without changing the bytes accessed
+        let uniform = || {
+            let mut mmu = Mmu::new(2 * DIRTY_BLOCK_SIZE);
+            mmu.set_permissions(VirtAddr(0), 2 * DIRTY_BLOCK_SIZE,
+                                Perm(PERM_READ | PERM_WRITE)).unwrap();
+            mmu.set_permissions(VirtAddr(2 * DIRTY_BLOCK_SIZE - 1), 1, Perm(0))
+                .unwrap();
+            mmu
+        };
+        let fast = VirtAddr(0x10);
+        let slow = VirtAddr(DIRTY_BLOCK_SIZE + 0x10);
+
+        let mut mmu = uniform();
+        assert!(mmu.read_fast_path(fast, 8, PERM_READ));
+        assert!(mmu.write_fast_path(fast, 8));
+        assert!(!mmu.read_fast_path(slow, 8, PERM_READ));
+        assert!(!mmu.write_fast_path(slow, 8));
+        assert!(!mmu.read_fast_path(VirtAddr(DIRTY_BLOCK_SIZE - 4), 8, PERM_READ));
+
+        // Both paths give the same results
+        for addr in [fast, slow] {
+            mmu.write::<u64>(addr, 0x1122334455667788).unwrap();
+            assert_eq!(mmu.read::<u64>(addr), Ok(0x1122334455667788));
+        }
+        assert_eq!(mmu.dirty.len(), 2);
+
+        // Anything which needs to see an access takes it off the fast paths
+        let mut mmu = uniform();
+        mmu.add_watchpoint(VirtAddr(DIRTY_BLOCK_SIZE), 8, Perm(PERM_WRITE)).unwrap();
+        assert!(!mmu.read_fast_path(fast, 8, PERM_READ));
+        assert!(!mmu.write_fast_path(fast, 8));
+
+        let mut mmu = uniform();
+        mmu.checkpoint();
+        assert!(mmu.read_fast_path(fast, 8, PERM_READ));
+        assert!(!mmu.write_fast_path(fast, 8));
+
+        let mut mmu = uniform();
+        mmu.enable_taint();
+        assert!(mmu.read_fast_path(fast, 8, PERM_READ));
+        assert!(!mmu.write_fast_path(fast, 8));
+
+        let mut mmu = uniform();
+        mmu.set_reset_verification(true);
+        assert!(mmu.read_fast_path(fast, 8, PERM_READ));
+        assert!(!mmu.write_fast_path(fast, 8));
+    }
+
+    #[test]
+    fn failed_allocation_keeps_the_break() {
+        let mut mmu = Mmu::new(0x4000);
+        let base = mmu.next_alloc();
+
+        assert_eq!(mmu.allocate(0x10_0000_0000, RegionKind::Heap), None);
+        assert_eq!(mmu.allocate(usize::MAX, RegionKind::Heap), None);
+        assert_eq!(mmu.next_alloc(), base);
+
+        // Memory can still be handed out up to the very end
+        assert_eq!(mmu.allocate(0x4000 - base.0, RegionKind::Heap), Some(base));
+        assert_eq!(mmu.allocate(0, RegionKind::Heap), Some(VirtAddr(0x4000)));
+        assert_eq!(mmu.allocate(1, RegionKind::Heap), None);
+        assert_eq!(mmu.next_alloc(), VirtAddr(0x4000));
+    }
+
+    #[test]
+    fn snapshots_with_bad_sizes_are_rejected() {
+        let mut mmu = Mmu::new(0x4000);
+        mmu.set_permissions(VirtAddr(0x1000), 8, Perm(PERM_WRITE)).unwrap();
+        let mut file = Vec::new();
+        mmu.save_snapshot(&mut file).unwrap();
+        let load = |file: &[u8]| Mmu::load_snapshot(&mut &file[..]).err().unwrap();
+
+        // Memory larger than the limit is refused before it is allocated
+        let mut huge = file.clone();
+        huge[..8].copy_from_slice(&(MAX_MEMORY_SIZE as u64 + 1).to_le_bytes());
+        assert_eq!(load(&huge).kind(), io::ErrorKind::InvalidData);
+
+        // Size, allocator, one block and its end marker come before the
+        // dirty list
+        let dirty = 8 + 8 + 8 + 2 * DIRTY_BLOCK_SIZE + 8;
+        assert_eq!(file[dirty..dirty + 16], [1, 0, 0, 0, 0, 0, 0, 0,
+                                             1, 0, 0, 0, 0, 0, 0, 0]);
+        for block in [4, u64::MAX / DIRTY_BLOCK_SIZE as u64 + 1, u64::MAX - 1] {
+            let mut bad = file.clone();
+            bad[dirty + 8..dirty + 16].copy_from_slice(&block.to_le_bytes());
+            assert_eq!(load(&bad).kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    /// A device which records every access and reads back `offset + 1` for
+    /// each byte
+    #[derive(Default)]
+    struct Recorder {
+        /// Offset, bytes and whether it was a write, of every access
+        accesses: Vec<(usize, Vec<u8>, bool)>,
+    }
+
+    impl MmioDevice for Recorder {
+        fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), VmExit> {
+            for (idx, x) in buf.iter_mut().enumerate() {
+                *x = (offset + idx + 1) as u8;
+            }
+            self.accesses.push((offset, buf.to_vec(), false));
+            Ok(())
+        }
+
+        fn write(&mut self, offset: usize, buf: &[u8]) -> Result<(), VmExit> {
+            self.accesses.push((offset, buf.to_vec(), true));
+            Ok(())
+        }
+    }
+
+    /// An MMU with readable and writable memory around a 16 byte device
+    /// region at 0x2000
+    fn mmio_guest() -> (Mmu, Arc<Mutex<Recorder>>) {
+        let mut mmu = Mmu::new(0x4000);
+        mmu.set_permissions(VirtAddr(0x1000), 0x2000,
+                            Perm(PERM_READ | PERM_WRITE)).unwrap();
+
+        let device = Arc::new(Mutex::new(Recorder::default()));
+        mmu.map_mmio(VirtAddr(0x2000), 0x10, Perm(PERM_READ | PERM_WRITE),
+                     device.clone()).unwrap();
+        (mmu, device)
+    }
+
+    #[test]
+    fn mmio_accesses_inside_the_region_reach_the_device() {
+        let (mut mmu, device) = mmio_guest();
 
+        mmu.write::<u32>(VirtAddr(0x2004), 0x11223344).unwrap();
+        assert_eq!(mmu.read::<u16>(VirtAddr(0x200e)).unwrap(), 0x100f);
+        assert_eq!(device.lock().unwrap().accesses, [
+            (4, vec![0x44, 0x33, 0x22, 0x11], true),
+            (14, vec![15, 16], false),
+        ]);
+
+        // Backing memory is never touched
+        assert_eq!(mmu.memory[0x2000..0x2010], [0u8; 16]);
+        assert!(mmu.peek(VirtAddr(0x2000), 4, Perm(PERM_READ)).is_err());
+    }
+
+    #[test]
+    fn mmio_accesses_straddling_memory_fault() {
+        let (mut mmu, device) = mmio_guest();
+        mmu.write_from(VirtAddr(0x1ff8), &[0xaa; 8]).unwrap();
+
+        // Into the region from memory below, and out of it into memory above
+        assert!(matches!(mmu.write::<u64>(VirtAddr(0x1ffc), !0),
+                         Err(VmExit::AddressMiss(VirtAddr(0x1ffc), 8, _))));
+        assert!(matches!(mmu.read::<u64>(VirtAddr(0x1ffc)),
+                         Err(VmExit::AddressMiss(VirtAddr(0x1ffc), 8, _))));
+        assert!(matches!(mmu.read::<u32>(VirtAddr(0x200e)),
+                         Err(VmExit::AddressMiss(VirtAddr(0x200e), 4, _))));
+
+        // Covering the whole region and more
+        let mut buf = [0u8; 0x20];
+        assert!(matches!(mmu.read_into(VirtAddr(0x1ff8), &mut buf),
+                         Err(VmExit::AddressMiss(VirtAddr(0x1ff8), 0x20, _))));
+
+        // Neither the device nor memory saw any part of the accesses
+        assert!(device.lock().unwrap().accesses.is_empty());
+        assert_eq!(mmu.memory[0x1ff8..0x2000], [0xaa; 8]);
+    }
+
+    #[test]
+    fn mmio_regions_may_not_overlap() {
+        let (mut mmu, _) = mmio_guest();
+        let other = Arc::new(Mutex::new(Recorder::default()));
+        let perm = Perm(PERM_READ | PERM_WRITE);
+
+        assert_eq!(mmu.map_mmio(VirtAddr(0x200f), 8, perm, other.clone()), None);
+        assert_eq!(mmu.map_mmio(VirtAddr(0x1ff8), 0x20, perm, other.clone()), None);
+        assert_eq!(mmu.map_mmio(VirtAddr(0x3ff8), 9, perm, other.clone()), None);
+        assert_eq!(mmu.map_mmio(VirtAddr(0x2010), 8, perm, other.clone()), Some(()));
+
+        // Adjacent regions each get their own accesses
+        mmu.write::<u8>(VirtAddr(0x2010), 7).unwrap();
+        assert!(matches!(mmu.write::<u16>(VirtAddr(0x200f), 7),
+                         Err(VmExit::AddressMiss(..))));
+        assert_eq!(other.lock().unwrap().accesses, [(0, vec![7], true)]);
+    }
+}
@@ -2,6 +2,10 @@
 //!
 
 
+/// # Safety
+///
+/// Implementors must be plain-old-data: every bit pattern is a valid value
+/// and the type contains no padding, so it can be read from raw guest bytes
 pub unsafe trait Primitive : Default + Clone + Copy {}
 unsafe impl Primitive for u8     {}
 unsafe impl Primitive for u16    {}
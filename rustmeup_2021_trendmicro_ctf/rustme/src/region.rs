@@ -0,0 +1,148 @@
+//! Named regions of guest memory, used to explain where faults landed
+
+use crate::mmu::VirtAddr;
+use std::fmt;
+
+/// What a region of guest memory holds
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RegionKind {
+    Text,
+    Data,
+    Bss,
+    Stack,
+    Heap,
+    Argv,
+    Mmap,
+
+    /// Unmapped memory below a stack, touching it is a stack overflow
+    Guard,
+}
+
+impl RegionKind {
+    /// Every kind, indexed by their snapshot encoding
+    pub const ALL: [RegionKind; 8] = [
+        RegionKind::Text, RegionKind::Data, RegionKind::Bss,
+        RegionKind::Stack, RegionKind::Heap, RegionKind::Argv,
+        RegionKind::Mmap, RegionKind::Guard,
+    ];
+
+    /// Name of the kind as used in fault reports
+    pub fn name(self) -> &'static str {
+        match self {
+            RegionKind::Text  => "text",
+            RegionKind::Data  => "data",
+            RegionKind::Bss   => "bss",
+            RegionKind::Stack => "stack",
+            RegionKind::Heap  => "heap",
+            RegionKind::Argv  => "argv",
+            RegionKind::Mmap  => "mmap",
+            RegionKind::Guard => "stack guard",
+        }
+    }
+}
+
+impl fmt::Display for RegionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A named range of guest memory
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Region {
+    /// What the region holds
+    pub kind: RegionKind,
+
+    /// First address of the region
+    pub start: VirtAddr,
+
+    /// One past the last address of the region
+    pub end: usize,
+}
+
+/// Where an address lies relative to the named regions
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Location {
+    /// `offset` bytes into `region`
+    Inside {
+        region: Region,
+        offset: usize,
+    },
+
+    /// `distance` bytes past the end of `region`, the nearest region. The
+    /// first byte after the region is 0 bytes past its end
+    After {
+        region:   Region,
+        distance: usize,
+    },
+
+    /// `distance` bytes before the start of `region`, the nearest region
+    Before {
+        region:   Region,
+        distance: usize,
+    },
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Location::Inside { region, offset } => {
+                write!(f, "offset {:#x} in {}", offset, region.kind)
+            }
+            Location::After { region, distance: 0 } => {
+                write!(f, "at end of {}", region.kind)
+            }
+            Location::After { region, distance } => {
+                write!(f, "{} bytes past end of {}", distance, region.kind)
+            }
+            Location::Before { region, distance } => {
+                write!(f, "{} bytes before start of {}", distance, region.kind)
+            }
+        }
+    }
+}
+
+/// Find where `addr` lies in `regions`. Addresses outside every region are
+/// placed relative to the nearest one. Returns `None` if there are no regions
+pub fn locate(regions: &[Region], addr: VirtAddr) -> Option<Location> {
+    if let Some(region) = regions.iter().rev()
+            .find(|x| addr.0 >= x.start.0 && addr.0 < x.end) {
+        return Some(Location::Inside {
+            region: *region, offset: addr.0 - region.start.0,
+        });
+    }
+
+    regions.iter().map(|region| {
+        if addr.0 >= region.end {
+            Location::After { region: *region, distance: addr.0 - region.end }
+        } else {
+            Location::Before { region: *region, distance: region.start.0 - addr.0 }
+        }
+    }).min_by_key(|x| match x {
+        Location::After { distance, .. } | Location::Before { distance, .. } => {
+            *distance
+        }
+        Location::Inside { .. } => 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locations_name_the_nearest_region() {
+        let regions = [
+            Region { kind: RegionKind::Text,  start: VirtAddr(0x1000), end: 0x2000 },
+            Region { kind: RegionKind::Stack, start: VirtAddr(0x8000), end: 0x9000 },
+        ];
+        let describe = |addr| locate(&regions, VirtAddr(addr)).unwrap().to_string();
+
+        assert_eq!(describe(0x1010), "offset 0x10 in text");
+        assert_eq!(describe(0x8fff), "offset 0xfff in stack");
+        assert_eq!(describe(0x9000), "at end of stack");
+        assert_eq!(describe(0x9004), "4 bytes past end of stack");
+        assert_eq!(describe(0x7ffc), "4 bytes before start of stack");
+        assert_eq!(describe(0x2010), "16 bytes past end of text");
+    }
+}
@@ -0,0 +1,49 @@
+//! Host side shadow call stack
+//!
+//! Calls push their return address on a stack the guest cannot touch, so a
+//! return to anywhere else means the guest's own stack was corrupted.
+
+use crate::mmu::VirtAddr;
+
+/// A call the guest has not returned from yet
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Frame {
+    /// Address of the call instruction
+    pub call_site: VirtAddr,
+
+    /// Address the call must return to
+    pub ret: VirtAddr,
+}
+
+/// Stack of the calls made by the guest, innermost last
+#[derive(Clone, Default, Debug)]
+pub struct ShadowStack {
+    frames: Vec<Frame>,
+}
+
+impl ShadowStack {
+    /// Create an empty shadow stack
+    pub fn new() -> Self {
+        ShadowStack::default()
+    }
+
+    /// Record a call at `call_site` which returns to `ret`
+    pub fn call(&mut self, call_site: VirtAddr, ret: VirtAddr) {
+        self.frames.push(Frame { call_site, ret });
+    }
+
+    /// Pop the innermost call for a return to `target`. Returns the frame
+    /// if it matches, otherwise `Err` with the frame that should have been
+    /// returned to, `None` if there are no calls to return from
+    pub fn ret(&mut self, target: VirtAddr) -> Result<Frame, Option<Frame>> {
+        match self.frames.pop() {
+            Some(frame) if frame.ret == target => Ok(frame),
+            frame => Err(frame),
+        }
+    }
+
+    /// Calls which have not returned yet, innermost last
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+}
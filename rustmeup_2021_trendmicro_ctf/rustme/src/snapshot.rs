@@ -0,0 +1,85 @@
+//! Versioned on-disk snapshots of an emulator
+//!
+//! A snapshot starts with `MAGIC` and a little endian `u32` version, followed
+//! by the emulator registers, the MMU state and the guest's files and
+//! descriptors. All integers are stored as little endian `u64`s, byte strings
+//! as their length followed by the bytes.
+
+use std::io::{self, Read, Write};
+use std::convert::TryFrom;
+
+/// Magic bytes at the start of every snapshot file
+pub const MAGIC: &[u8; 8] = b"RUSTMESN";
+
+/// Version of the snapshot format written by this build
+pub const VERSION: u32 = 3;
+
+/// Build an error for a malformed snapshot
+pub fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Write the snapshot header
+pub fn write_header<W: Write>(w: &mut W) -> io::Result<()> {
+    w.write_all(MAGIC)?;
+    w.write_all(&VERSION.to_le_bytes())
+}
+
+/// Read and validate the snapshot header
+pub fn read_header<R: Read>(r: &mut R) -> io::Result<()> {
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a snapshot file"));
+    }
+
+    let mut version = [0u8; 4];
+    r.read_exact(&mut version)?;
+    if u32::from_le_bytes(version) != VERSION {
+        return Err(invalid("unsupported snapshot version"));
+    }
+
+    Ok(())
+}
+
+/// Write a `u64`
+pub fn write_u64<W: Write>(w: &mut W, val: u64) -> io::Result<()> {
+    w.write_all(&val.to_le_bytes())
+}
+
+/// Read a `u64`
+pub fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut tmp = [0u8; 8];
+    r.read_exact(&mut tmp)?;
+    Ok(u64::from_le_bytes(tmp))
+}
+
+/// Read a `u64` which is used as a size or index into host memory
+pub fn read_usize<R: Read>(r: &mut R) -> io::Result<usize> {
+    let val = read_u64(r)?;
+    usize::try_from(val).map_err(|_| invalid("value out of range"))
+}
+
+/// Write a length prefixed byte string
+pub fn write_bytes<W: Write>(w: &mut W, bytes: &[u8]) -> io::Result<()> {
+    write_u64(w, bytes.len() as u64)?;
+    w.write_all(bytes)
+}
+
+/// Read a length prefixed byte string of at most `max` bytes
+pub fn read_bytes<R: Read>(r: &mut R, max: usize) -> io::Result<Vec<u8>> {
+    let len = read_usize(r)?;
+    if len > max {
+        return Err(invalid("byte string too long"));
+    }
+
+    let mut bytes = vec![0u8; len];
+    r.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Read a length prefixed UTF-8 string of at most `max` bytes
+pub fn read_string<R: Read>(r: &mut R, max: usize) -> io::Result<String> {
+    String::from_utf8(read_bytes(r, max)?)
+        .map_err(|_| invalid("string is not UTF-8"))
+}
@@ -0,0 +1,85 @@
+//! Linux compatible initial process stack
+//!
+//! At entry `sp` points at `argc`, followed by the NULL terminated `argv` and
+//! `envp` pointer arrays and the auxiliary vector. The strings and the
+//! `AT_RANDOM` bytes live in an argv region allocated next to the stack.
+
+use crate::emulator::{Emulator, Register};
+use crate::elf::{Elf, PAGE_SIZE};
+use crate::mmu::VirtAddr;
+use crate::region::RegionKind;
+
+/// Auxiliary vector entry types
+const AT_NULL:   u64 = 0;
+const AT_PHDR:   u64 = 3;
+const AT_PHENT:  u64 = 4;
+const AT_PHNUM:  u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY:  u64 = 9;
+const AT_HWCAP:  u64 = 16;
+const AT_RANDOM: u64 = 25;
+
+/// ISA extensions implemented by the emulator, one bit per letter. Only the
+/// base integer ISA is supported
+const HWCAP: u64 = 1 << (b'I' - b'A');
+
+/// Build the initial stack for `elf` below the stack pointer with `argv`,
+/// `envp` and the 16 `random` bytes for `AT_RANDOM`. Returns `None` if the
+/// strings can't be allocated or the stack is too small
+pub fn init_stack(emu: &mut Emulator, elf: &Elf,
+                  argv: &[&[u8]], envp: &[&[u8]], random: &[u8; 16])
+        -> Option<()> {
+
+    // Copy the random bytes and NUL terminated strings into the argv region,
+    // rounded up to a page to keep the break that follows aligned
+    let size = random.len() + argv.iter().chain(envp)
+        .map(|x| x.len() + 1).sum::<usize>();
+    let size = (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    let base = emu.memory.allocate(size, RegionKind::Argv)?;
+    emu.memory.write_from(base, random).ok()?;
+
+    let mut cur = base.0 + random.len();
+    let mut strings = |emu: &mut Emulator, list: &[&[u8]]| -> Option<Vec<u64>> {
+        let mut ptrs = Vec::new();
+        for string in list {
+            emu.memory.write_from(VirtAddr(cur), string).ok()?;
+            emu.memory.write_from(VirtAddr(cur + string.len()), &[0]).ok()?;
+            ptrs.push(cur as u64);
+            cur += string.len() + 1;
+        }
+        Some(ptrs)
+    };
+    let argv_ptrs = strings(emu, argv)?;
+    let envp_ptrs = strings(emu, envp)?;
+
+    let auxv = [
+        (AT_PHDR,   elf.phdr.map_or(0, |x| x.0 as u64)),
+        (AT_PHENT,  elf.phent as u64),
+        (AT_PHNUM,  elf.phnum as u64),
+        (AT_PAGESZ, PAGE_SIZE as u64),
+        (AT_ENTRY,  elf.entry.0 as u64),
+        (AT_RANDOM, base.0 as u64),
+        (AT_HWCAP,  HWCAP),
+        (AT_NULL,   0),
+    ];
+
+    let mut words = vec![argv_ptrs.len() as u64];
+    words.extend(&argv_ptrs);
+    words.push(0);
+    words.extend(&envp_ptrs);
+    words.push(0);
+    for &(key, val) in &auxv {
+        words.push(key);
+        words.push(val);
+    }
+
+    // The ABI wants `sp` 16 byte aligned at entry
+    let sp = (emu.reg(Register::Sp) as usize)
+        .checked_sub(words.len() * 8)? & !0xf;
+    for (idx, &word) in words.iter().enumerate() {
+        emu.memory.write(VirtAddr(sp + idx * 8), word).ok()?;
+    }
+    emu.set_reg(Register::Sp, sp as u64);
+
+    Some(())
+}
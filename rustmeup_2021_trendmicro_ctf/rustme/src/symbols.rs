@@ -0,0 +1,573 @@
+//! Guest symbols and line numbers, for turning raw addresses into names
+//!
+//! Symbols come from `.symtab`, line numbers from the DWARF line programs in
+//! `.debug_line` (versions 2 through 5, 32-bit DWARF only). Both are optional,
+//! a stripped guest simply has no names.
+
+use crate::elf;
+use crate::mmu::VirtAddr;
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+
+/// `st_info` types kept in the symbol table
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC:   u8 = 2;
+
+/// Size of an ELF64 symbol table entry
+const SYM_SIZE: usize = 24;
+
+/// A named function or object in the guest
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: VirtAddr,
+
+    /// Size in bytes, zero if unknown
+    pub size: usize,
+}
+
+/// One row of a line table, the code from `addr` up to the next row
+#[derive(Clone, Copy, Debug)]
+struct LineRow {
+    addr: usize,
+
+    /// Index into `Symbols::files`
+    file: usize,
+    line: u32,
+
+    /// Marks the first address past the end of a sequence
+    end: bool,
+}
+
+/// Symbol and line information of a guest
+#[derive(Default)]
+pub struct Symbols {
+    /// Symbols sorted by address
+    symbols: Vec<Symbol>,
+
+    /// Index into `symbols` by name
+    by_name: HashMap<String, usize>,
+
+    /// Line table rows of all sequences, sorted by address
+    lines: Vec<LineRow>,
+
+    /// File names referenced by `lines`
+    files: Vec<String>,
+
+    /// Index into `files` by name
+    file_idx: HashMap<String, usize>,
+
+    /// Load base added to all link addresses
+    base: usize,
+}
+
+/// Where an address is in the guest source
+pub struct CodeLocation<'a> {
+    /// Symbol containing the address and the offset into it
+    pub symbol: Option<(&'a Symbol, usize)>,
+
+    /// File and line of the address
+    pub line: Option<(&'a str, u32)>,
+}
+
+impl fmt::Display for CodeLocation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.symbol {
+            Some((sym, 0))   => write!(f, "<{}>", sym.name)?,
+            Some((sym, off)) => write!(f, "<{}+{:#x}>", sym.name, off)?,
+            None             => write!(f, "<unknown>")?,
+        }
+        if let Some((file, line)) = self.line {
+            write!(f, " ({}:{})", file, line)?;
+        }
+        Ok(())
+    }
+}
+
+impl Symbols {
+    /// Read the symbols and line tables of the ELF in `image`, loaded `base`
+    /// bytes above its link addresses. Missing or malformed tables are skipped
+    pub fn parse(image: &[u8], base: usize) -> Self {
+        let mut symbols = Symbols { base, ..Symbols::default() };
+
+        if let (Some(symtab), Some(strtab)) =
+                (elf::section(image, ".symtab"), elf::section(image, ".strtab")) {
+            symbols.parse_symtab(symtab, strtab);
+        }
+
+        if let Some(debug_line) = elf::section(image, ".debug_line") {
+            let strs = LineStrings {
+                line_str: elf::section(image, ".debug_line_str").unwrap_or(&[]),
+                str:      elf::section(image, ".debug_str").unwrap_or(&[]),
+            };
+            let mut off = 0;
+            while off < debug_line.len() {
+                match symbols.parse_line_unit(debug_line, off, &strs) {
+                    Some(next) => off = next,
+                    None       => break,
+                }
+            }
+
+            // Sort by address with sequence ends first, so a sequence
+            // starting where another ends wins
+            symbols.lines.sort_by_key(|x| (x.addr, !x.end));
+        }
+
+        symbols
+    }
+
+    /// Read the functions and objects out of `symtab`
+    fn parse_symtab(&mut self, symtab: &[u8], strtab: &[u8]) {
+        for sym in symtab.chunks_exact(SYM_SIZE) {
+            let name  = u32::from_le_bytes(sym[0..4].try_into().unwrap()) as usize;
+            let kind  = sym[4] & 0xf;
+            let shndx = u16::from_le_bytes(sym[6..8].try_into().unwrap());
+            let addr  = u64::from_le_bytes(sym[8..16].try_into().unwrap());
+            let size  = u64::from_le_bytes(sym[16..24].try_into().unwrap());
+
+            // Only defined code and data
+            if shndx == 0 || ![STT_NOTYPE, STT_OBJECT, STT_FUNC].contains(&kind) {
+                continue;
+            }
+
+            let name = match strtab.get(name..).and_then(cstr) {
+                Some(name) if !name.is_empty() && !name.starts_with(".L") &&
+                    !name.starts_with('$') => name,
+                _ => continue,
+            };
+
+            self.symbols.push(Symbol {
+                name: name.into(),
+                addr: VirtAddr((addr as usize).wrapping_add(self.base)),
+                size: size as usize,
+            });
+        }
+
+        self.symbols.sort_by_key(|x| (x.addr, x.size != 0));
+        for (idx, sym) in self.symbols.iter().enumerate() {
+            self.by_name.entry(sym.name.clone()).or_insert(idx);
+        }
+    }
+
+    /// Run the line program of the unit at `off`, returning the offset of
+    /// the next unit
+    fn parse_line_unit(&mut self, data: &[u8], off: usize,
+                       strs: &LineStrings) -> Option<usize> {
+        let mut r = Reader { data, pos: off };
+        let unit_length = r.u32()? as usize;
+        if unit_length >= 0xfffffff0 {
+            // 64-bit DWARF isn't supported
+            return None;
+        }
+        let end = r.pos.checked_add(unit_length).filter(|&x| x <= data.len())?;
+        let next = end;
+
+        let version = r.u16()?;
+        if !(2..=5).contains(&version) {
+            return Some(next);
+        }
+        if version >= 5 {
+            let _address_size = r.u8()?;
+            let _seg_sel_size = r.u8()?;
+        }
+        let header_length = r.u32()? as usize;
+        let program = r.pos.checked_add(header_length)?;
+
+        let min_inst = r.u8()? as usize;
+        if version >= 4 {
+            let _max_ops = r.u8()?;
+        }
+        let default_is_stmt = r.u8()? != 0;
+        let line_base = r.u8()? as i8 as i64;
+        let line_range = r.u8()?;
+        let opcode_base = r.u8()?;
+        if line_range == 0 || opcode_base == 0 {
+            return Some(next);
+        }
+        let mut opcode_lengths = Vec::new();
+        for _ in 1..opcode_base {
+            opcode_lengths.push(r.u8()?);
+        }
+
+        // Files of this unit, as indices into `self.files`
+        let mut files = Vec::new();
+        if version >= 5 {
+            let dirs = r.entries(strs)?
+                .into_iter().map(|(path, _)| path).collect::<Vec<_>>();
+            for (name, dir) in r.entries(strs)? {
+                files.push(self.add_file(dirs.get(dir).map(|x| x.as_str()), &name));
+            }
+        } else {
+            // File 0 doesn't exist before version 5
+            files.push(self.add_file(None, "<unknown>"));
+
+            let mut dirs = Vec::new();
+            loop {
+                let dir = r.cstr()?;
+                if dir.is_empty() {
+                    break;
+                }
+                dirs.push(dir.to_string());
+            }
+            loop {
+                let name = r.cstr()?;
+                if name.is_empty() {
+                    break;
+                }
+                let dir = r.uleb()? as usize;
+                let _mtime = r.uleb()?;
+                let _length = r.uleb()?;
+                let dir = dir.checked_sub(1).and_then(|x| dirs.get(x));
+                files.push(self.add_file(dir.map(|x| x.as_str()), name));
+            }
+        }
+
+        // Run the line number state machine
+        r.pos = program;
+        let mut addr = 0usize;
+        let mut file = 1usize;
+        let mut line = 1i64;
+        let mut is_stmt = default_is_stmt;
+        while r.pos < end {
+            let op = r.u8()?;
+            let mut emit = false;
+            let mut end_seq = false;
+
+            if op >= opcode_base {
+                // Special opcode, advance both address and line
+                let adj = op - opcode_base;
+                addr = addr.wrapping_add((adj / line_range) as usize * min_inst);
+                line = line.checked_add(line_base + (adj % line_range) as i64)?;
+                emit = true;
+            } else {
+                match op {
+                    0 => {
+                        // Extended opcode
+                        let len = r.uleb()? as usize;
+                        let start = r.pos;
+                        match r.u8()? {
+                            1 => { emit = true; end_seq = true; }
+                            2 => addr = r.u64()? as usize,
+                            3 => {
+                                let name = r.cstr()?;
+                                files.push(self.add_file(None, name));
+                            }
+                            _ => {}
+                        }
+                        r.pos = start.checked_add(len)?;
+                    }
+                    1 => emit = true,
+                    2 => addr = addr.wrapping_add(r.uleb()? as usize * min_inst),
+                    3 => line = line.checked_add(r.sleb()?)?,
+                    4 => file = r.uleb()? as usize,
+                    6 => is_stmt = !is_stmt,
+                    8 => {
+                        let adj = 255 - opcode_base;
+                        addr = addr.wrapping_add((adj / line_range) as usize * min_inst);
+                    }
+                    9 => addr = addr.wrapping_add(r.u16()? as usize),
+                    _ => {
+                        // Skip the operands of anything not affecting rows
+                        for _ in 0..opcode_lengths[op as usize - 1] {
+                            r.uleb()?;
+                        }
+                    }
+                }
+            }
+
+            if emit && (is_stmt || end_seq) {
+                self.lines.push(LineRow {
+                    addr: addr.wrapping_add(self.base),
+                    file: files.get(file).copied().unwrap_or(0),
+                    line: u32::try_from(line).unwrap_or(0),
+                    end:  end_seq,
+                });
+            }
+            if end_seq {
+                addr = 0;
+                file = 1;
+                line = 1;
+                is_stmt = default_is_stmt;
+            }
+        }
+
+        Some(next)
+    }
+
+    /// Intern the file `name` in `dir`, returning its index
+    fn add_file(&mut self, dir: Option<&str>, name: &str) -> usize {
+        let path = match dir {
+            Some(dir) if !name.starts_with('/') && !dir.is_empty() => {
+                format!("{}/{}", dir, name)
+            }
+            _ => name.to_string(),
+        };
+
+        let files = &mut self.files;
+        *self.file_idx.entry(path).or_insert_with_key(|path| {
+            files.push(path.clone());
+            files.len() - 1
+        })
+    }
+
+    /// Find the symbol containing `addr` and the offset of `addr` into it
+    pub fn symbol(&self, addr: VirtAddr) -> Option<(&Symbol, usize)> {
+        let idx = self.symbols.partition_point(|x| x.addr <= addr)
+            .checked_sub(1)?;
+        let sym = &self.symbols[idx];
+        let off = addr.0 - sym.addr.0;
+
+        // Symbols without a size extend up to the next symbol
+        if sym.size != 0 && off >= sym.size {
+            return None;
+        }
+        Some((sym, off))
+    }
+
+    /// Find the source file and line of `addr`
+    pub fn line(&self, addr: VirtAddr) -> Option<(&str, u32)> {
+        let idx = self.lines.partition_point(|x| x.addr <= addr.0)
+            .checked_sub(1)?;
+        let row = &self.lines[idx];
+        if row.end {
+            return None;
+        }
+        Some((&self.files[row.file], row.line))
+    }
+
+    /// Describe `addr` with its symbol and line
+    pub fn locate(&self, addr: VirtAddr) -> CodeLocation<'_> {
+        CodeLocation { symbol: self.symbol(addr), line: self.line(addr) }
+    }
+
+    /// Find the address of the symbol `name`
+    pub fn address(&self, name: &str) -> Option<VirtAddr> {
+        self.by_name.get(name).map(|&x| self.symbols[x].addr)
+    }
+
+    /// All symbols, sorted by address
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+}
+
+/// String sections referenced by DWARF 5 line tables
+struct LineStrings<'a> {
+    line_str: &'a [u8],
+    str:      &'a [u8],
+}
+
+/// Read a NUL terminated string from the start of `data`
+fn cstr(data: &[u8]) -> Option<&str> {
+    let len = data.iter().position(|&x| x == 0)?;
+    std::str::from_utf8(&data[..len]).ok()
+}
+
+/// Cursor over little endian DWARF data
+struct Reader<'a> {
+    data: &'a [u8],
+    pos:  usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|x| x[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|x| u16::from_le_bytes(x.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4).map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.bytes(8).map(|x| u64::from_le_bytes(x.try_into().unwrap()))
+    }
+
+    fn uleb(&mut self) -> Option<u64> {
+        let mut val = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            val |= ((byte & 0x7f) as u64) << shift;
+            if (byte & 0x80) == 0 {
+                return Some(val);
+            }
+        }
+        None
+    }
+
+    fn sleb(&mut self) -> Option<i64> {
+        let mut val = 0i64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            val |= ((byte & 0x7f) as i64).checked_shl(shift)?;
+            shift += 7;
+            if (byte & 0x80) == 0 {
+                if shift < 64 && (byte & 0x40) != 0 {
+                    val |= -1 << shift;
+                }
+                return Some(val);
+            }
+        }
+    }
+
+    fn cstr(&mut self) -> Option<&'a str> {
+        let s = cstr(&self.data[self.pos..])?;
+        self.pos += s.len() + 1;
+        Some(s)
+    }
+
+    /// Read a DWARF 5 directory or file name table, returning the path and
+    /// directory index of every entry
+    fn entries(&mut self, strs: &LineStrings<'a>)
+            -> Option<Vec<(String, usize)>> {
+        /// Entry content types
+        const DW_LNCT_PATH: u64 = 1;
+        const DW_LNCT_DIRECTORY_INDEX: u64 = 2;
+
+        let mut formats = Vec::new();
+        for _ in 0..self.u8()? {
+            formats.push((self.uleb()?, self.uleb()?));
+        }
+
+        let mut entries = Vec::new();
+        for _ in 0..self.uleb()? {
+            let mut path = String::new();
+            let mut dir = 0;
+            for &(content, form) in &formats {
+                let val = self.form(form, strs)?;
+                match (content, val) {
+                    (DW_LNCT_PATH, FormValue::Str(s)) => path = s.into(),
+                    (DW_LNCT_DIRECTORY_INDEX, FormValue::Num(x)) => dir = x as usize,
+                    _ => {}
+                }
+            }
+            entries.push((path, dir));
+        }
+        Some(entries)
+    }
+
+    /// Read an attribute of `form`, only forms used by line tables are known
+    fn form(&mut self, form: u64, strs: &LineStrings<'a>)
+            -> Option<FormValue<'a>> {
+        Some(match form {
+            0x08 => FormValue::Str(self.cstr()?),
+            0x1f => FormValue::Str(cstr(strs.line_str.get(self.u32()? as usize..)?)?),
+            0x0e => FormValue::Str(cstr(strs.str.get(self.u32()? as usize..)?)?),
+            0x0b => FormValue::Num(self.u8()? as u64),
+            0x05 => FormValue::Num(self.u16()? as u64),
+            0x06 => FormValue::Num(self.u32()? as u64),
+            0x07 => FormValue::Num(self.u64()?),
+            0x0f => FormValue::Num(self.uleb()?),
+            0x1e => { self.bytes(16)?; FormValue::Other }
+            0x09 => {
+                let len = self.uleb()? as usize;
+                self.bytes(len)?;
+                FormValue::Other
+            }
+            _ => return None,
+        })
+    }
+}
+
+/// Value of a DWARF attribute
+enum FormValue<'a> {
+    Str(&'a str),
+    Num(u64),
+    Other,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A version 2 line table unit for the file `a.c` running `program`
+    fn line_unit(program: &[u8]) -> Vec<u8> {
+        let mut header = vec![
+            1,                          // minimum_instruction_length
+            1,                          // default_is_stmt
+            -5i8 as u8,                 // line_base
+            14,                         // line_range
+            13,                         // opcode_base
+            0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1,
+            0,                          // no include directories
+        ];
+        header.extend_from_slice(b"a.c\0\0\0\0\0");
+
+        let mut unit = 2u16.to_le_bytes().to_vec();
+        unit.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        unit.extend_from_slice(&header);
+        unit.extend_from_slice(program);
+
+        let mut data = (unit.len() as u32).to_le_bytes().to_vec();
+        data.extend_from_slice(&unit);
+        data
+    }
+
+    /// Line program setting the address to 0x1000, advancing the line by
+    /// `advance` and emitting a row
+    fn advance_line(advance: &[u8]) -> Vec<u8> {
+        let mut program = vec![0, 9, 2];
+        program.extend_from_slice(&0x1000u64.to_le_bytes());
+        program.push(3);
+        program.extend_from_slice(advance);
+        program.push(1);
+        program
+    }
+
+    #[test]
+    fn files_are_interned() {
+        let mut symbols = Symbols::default();
+        let a = symbols.add_file(Some("src"), "a.c");
+        let b = symbols.add_file(None, "b.c");
+
+        assert_eq!(symbols.add_file(None, "src/a.c"), a);
+        assert_eq!(symbols.add_file(Some("/elsewhere"), "b.c"), b + 1);
+        assert_eq!(symbols.add_file(Some("src"), "a.c"), a);
+        assert_eq!(symbols.files, ["src/a.c", "b.c", "/elsewhere/b.c"]);
+
+        // Every unit naming the same file shares its entry
+        let unit = line_unit(&advance_line(&[9]));
+        let mut data = unit.clone();
+        data.extend_from_slice(&unit);
+        let mut symbols = Symbols::default();
+        let mut off = 0;
+        while off < data.len() {
+            off = symbols.parse_line_unit(&data, off, &LineStrings {
+                line_str: &[], str: &[],
+            }).unwrap();
+        }
+        assert_eq!(symbols.files, ["<unknown>", "a.c"]);
+        assert_eq!(symbols.lines.len(), 2);
+    }
+
+    #[test]
+    fn line_overflow_is_malformed() {
+        let strs = LineStrings { line_str: &[], str: &[] };
+
+        // Advance by 2^62 twice
+        let advance = [0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0xc0, 0];
+        let mut program = advance_line(&advance);
+        program.push(3);
+        program.extend_from_slice(&advance);
+
+        let mut symbols = Symbols::default();
+        assert_eq!(symbols.parse_line_unit(&line_unit(&program), 0, &strs), None);
+
+        let mut symbols = Symbols::default();
+        let unit = line_unit(&advance_line(&advance));
+        assert_eq!(symbols.parse_line_unit(&unit, 0, &strs), Some(unit.len()));
+        assert_eq!(symbols.lines[0].line, 0);
+    }
+}
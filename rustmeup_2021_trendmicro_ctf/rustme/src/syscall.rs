@@ -0,0 +1,378 @@
+//! System call dispatch
+//!
+//! Every system call number maps to a `SyscallHandler`. `Syscalls::linux`
+//! registers Linux compatible defaults, and challenges replace or add single
+//! calls with `register` on top of them. The default handlers are public so
+//! an override can fall back to them for the cases it doesn't care about.
+//!
+//! Like the kernel, handlers report failure by returning a negated errno in
+//! `a0`. Bad guest pointers give `-EFAULT` rather than stopping the VM.
+
+use crate::emulator::{Emulator, Register, VmExit};
+use crate::mmu::{VirtAddr, Perm, PERM_READ};
+use crate::region::RegionKind;
+use crate::vfs::{Target, VfsError, SEEK_CUR};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::time::SystemTime;
+
+/// System call numbers of the Linux defaults
+pub const SYS_DUP:          u64 = 23;
+pub const SYS_OPENAT:       u64 = 56;
+pub const SYS_CLOSE:        u64 = 57;
+pub const SYS_LSEEK:        u64 = 62;
+pub const SYS_READ:         u64 = 63;
+pub const SYS_WRITE:        u64 = 64;
+pub const SYS_FSTAT:        u64 = 80;
+pub const SYS_EXIT:         u64 = 93;
+pub const SYS_EXIT_GROUP:   u64 = 94;
+pub const SYS_GETTIMEOFDAY: u64 = 169;
+pub const SYS_BRK:          u64 = 214;
+
+/// Linux error numbers, returned to the guest negated
+pub const ENOENT:       u64 = 2;
+pub const EIO:          u64 = 5;
+pub const EBADF:        u64 = 9;
+pub const EACCES:       u64 = 13;
+pub const EFAULT:       u64 = 14;
+pub const EEXIST:       u64 = 17;
+pub const EINVAL:       u64 = 22;
+pub const EMFILE:       u64 = 24;
+pub const EFBIG:        u64 = 27;
+pub const ESPIPE:       u64 = 29;
+pub const EROFS:        u64 = 30;
+pub const ENAMETOOLONG: u64 = 36;
+pub const ENOSYS:       u64 = 38;
+
+/// `dirfd` of `openat` for paths relative to the working directory
+const AT_FDCWD: i64 = -100;
+
+/// Longest path accepted from the guest, including the NUL
+pub const PATH_MAX: usize = 4096;
+
+/// Something which can carry out a system call
+pub trait SyscallHandler {
+    /// Handle the system call `emu` stopped at. The arguments are in `a0` to
+    /// `a5` and the result goes in `a0`. PC is advanced past the `ecall` by
+    /// the caller
+    fn handle(&mut self, emu: &mut Emulator) -> Result<(), VmExit>;
+}
+
+impl<F> SyscallHandler for F
+        where F: FnMut(&mut Emulator) -> Result<(), VmExit> {
+    fn handle(&mut self, emu: &mut Emulator) -> Result<(), VmExit> {
+        self(emu)
+    }
+}
+
+/// What to do with a system call which has no handler
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum UnknownPolicy {
+    /// Return `-ENOSYS` like the kernel does
+    #[default]
+    Enosys,
+
+    /// Stop the VM with `VmExit::UnknownSyscall`
+    Exit,
+
+    /// Print the call and its arguments to stderr, then return `-ENOSYS`
+    Log,
+}
+
+/// Handlers keyed by system call number
+#[derive(Default)]
+pub struct Syscalls {
+    handlers: BTreeMap<u64, Box<dyn SyscallHandler>>,
+
+    /// What happens to system calls without a handler
+    unknown: UnknownPolicy,
+}
+
+impl Syscalls {
+    /// Create a registry without any system calls
+    pub fn new() -> Self {
+        Syscalls::default()
+    }
+
+    /// Create a registry with the Linux compatible defaults
+    pub fn linux() -> Self {
+        let mut syscalls = Syscalls::new();
+        syscalls.register(SYS_DUP,          dup);
+        syscalls.register(SYS_OPENAT,       openat);
+        syscalls.register(SYS_CLOSE,        close);
+        syscalls.register(SYS_LSEEK,        lseek);
+        syscalls.register(SYS_READ,         read);
+        syscalls.register(SYS_WRITE,        write);
+        syscalls.register(SYS_FSTAT,        fstat);
+        syscalls.register(SYS_EXIT,         exit);
+        syscalls.register(SYS_EXIT_GROUP,   exit);
+        syscalls.register(SYS_GETTIMEOFDAY, gettimeofday);
+        syscalls.register(SYS_BRK,          brk);
+        syscalls
+    }
+
+    /// Handle system call `num` with `handler`, returning the handler it
+    /// replaces
+    pub fn register<H>(&mut self, num: u64, handler: H)
+            -> Option<Box<dyn SyscallHandler>>
+            where H: SyscallHandler + 'static {
+        self.handlers.insert(num, Box::new(handler))
+    }
+
+    /// Stop handling system call `num`, returning its handler
+    pub fn unregister(&mut self, num: u64) -> Option<Box<dyn SyscallHandler>> {
+        self.handlers.remove(&num)
+    }
+
+    /// Handle system calls without a handler as `policy` says
+    pub fn set_unknown_policy(&mut self, policy: UnknownPolicy) {
+        self.unknown = policy;
+    }
+
+    /// Carry out the system call `emu` stopped at, as given by `a7`
+    pub fn dispatch(&mut self, emu: &mut Emulator) -> Result<(), VmExit> {
+        let num = emu.reg(Register::A7);
+
+        if let Some(handler) = self.handlers.get_mut(&num) {
+            return handler.handle(emu);
+        }
+
+        let pc = emu.reg(Register::Pc);
+        match self.unknown {
+            UnknownPolicy::Enosys => {}
+            UnknownPolicy::Exit => {
+                return Err(VmExit::UnknownSyscall {
+                    num,
+                    pc: VirtAddr(pc as usize),
+                });
+            }
+            UnknownPolicy::Log => {
+                eprintln!("unknown system call {} at {:#x} ({:#x}, {:#x}, {:#x}, \
+                           {:#x}, {:#x}, {:#x})", num, pc,
+                          emu.reg(Register::A0), emu.reg(Register::A1),
+                          emu.reg(Register::A2), emu.reg(Register::A3),
+                          emu.reg(Register::A4), emu.reg(Register::A5));
+            }
+        }
+        set_result(emu, Err(ENOSYS));
+        Ok(())
+    }
+}
+
+/// `brk(addr)`, growing the heap up to `addr`. Like the kernel it returns
+/// the new break, or the current one if the heap can't be moved to `addr`
+pub fn brk(emu: &mut Emulator) -> Result<(), VmExit> {
+    let req_base = emu.reg(Register::A0);
+    let cur_base = emu.memory.next_alloc().0 as u64;
+
+    // The heap never shrinks, so lower requests just query the break
+    let new_base = req_base.checked_sub(cur_base)
+        .and_then(|increment| {
+            emu.memory.allocate(increment as usize, RegionKind::Heap)
+        })
+        .map_or(cur_base, |_| req_base);
+
+    emu.set_reg(Register::A0, new_base);
+    Ok(())
+}
+
+/// Set `a0` to the result of a system call, the negated errno on failure
+fn set_result(emu: &mut Emulator, result: Result<u64, u64>) {
+    emu.set_reg(Register::A0, result.unwrap_or_else(|errno| errno.wrapping_neg()));
+}
+
+/// Read the NUL terminated path at `addr` out of guest memory
+fn read_path(emu: &mut Emulator, addr: usize) -> Result<Vec<u8>, u64> {
+    let mut path = Vec::new();
+    while path.len() < PATH_MAX {
+        let byte = emu.memory.read::<u8>(VirtAddr(addr.wrapping_add(path.len())))
+            .map_err(|_| EFAULT)?;
+        if byte == 0 {
+            return Ok(path);
+        }
+        path.push(byte);
+    }
+    Err(ENAMETOOLONG)
+}
+
+/// `openat(dirfd, path, flags, mode)`. The working directory is the root, so
+/// only `AT_FDCWD` and absolute paths are supported
+pub fn openat(emu: &mut Emulator) -> Result<(), VmExit> {
+    let dirfd = emu.reg(Register::A0) as i64;
+    let path  = emu.reg(Register::A1) as usize;
+    let flags = emu.reg(Register::A2);
+
+    let result = read_path(emu, path).and_then(|path| {
+        if dirfd == AT_FDCWD || path.starts_with(b"/") {
+            emu.vfs.open(&path, flags).map_err(VfsError::errno)
+        } else {
+            Err(EBADF)
+        }
+    });
+    set_result(emu, result);
+    Ok(())
+}
+
+/// `open(path, flags, mode)` of newlib's legacy system calls. Not one of the
+/// Linux defaults, register it for guests which need it
+pub fn open(emu: &mut Emulator) -> Result<(), VmExit> {
+    let path  = emu.reg(Register::A0) as usize;
+    let flags = emu.reg(Register::A1);
+
+    let result = read_path(emu, path).and_then(|path| {
+        emu.vfs.open(&path, flags).map_err(VfsError::errno)
+    });
+    set_result(emu, result);
+    Ok(())
+}
+
+/// `read(fd, buf, count)`. Stdin is read from the emulator's `Input`, at
+/// most `count` bytes unless it is in line mode. Bytes read are tainted as
+/// input. Bytes which can't be stored in `buf` are left to be read again
+pub fn read(emu: &mut Emulator) -> Result<(), VmExit> {
+    let fd    = emu.reg(Register::A0);
+    let buf   = emu.reg(Register::A1) as usize;
+    let count = emu.reg(Register::A2) as usize;
+
+    let result = match emu.vfs.target(fd) {
+        Some(Target::Stdin) => {
+            emu.stdin.lock().unwrap().read(count).map_err(|_| EIO)
+        }
+        Some(Target::File(_)) => emu.vfs.read(fd, count).map_err(VfsError::errno),
+        _ => Err(EBADF),
+    };
+
+    let result = result.and_then(|data| {
+        if emu.memory.write_from(VirtAddr(buf), &data).is_err() {
+            // The guest never got the bytes, give them back
+            if emu.vfs.target(fd) == Some(&Target::Stdin) {
+                emu.stdin.lock().unwrap().unread(&data);
+            } else {
+                emu.vfs.lseek(fd, -(data.len() as i64), SEEK_CUR)
+                    .expect("Failed to rewind after a faulting read");
+            }
+            return Err(EFAULT);
+        }
+        emu.taint_input(VirtAddr(buf), data.len());
+        Ok(data.len() as u64)
+    });
+    set_result(emu, result);
+    Ok(())
+}
+
+/// `write(fd, buf, count)` to a file, stdout or stderr. Like Linux, a bad
+/// descriptor is reported before a bad buffer
+pub fn write(emu: &mut Emulator) -> Result<(), VmExit> {
+    let fd  = emu.reg(Register::A0);
+    let buf = emu.reg(Register::A1);
+    let len = emu.reg(Register::A2);
+
+    if let Err(err) = emu.vfs.writable(fd) {
+        set_result(emu, Err(err.errno()));
+        return Ok(());
+    }
+    let bytes = match emu.memory.peek(VirtAddr(buf as usize), len as usize,
+                                      Perm(PERM_READ)) {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            set_result(emu, Err(EFAULT));
+            return Ok(());
+        }
+    };
+    let result = match emu.vfs.target(fd) {
+        Some(Target::Stdout) => {
+            io::stdout().write_all(bytes).and_then(|_| io::stdout().flush())
+                .map(|_| len).map_err(|_| EIO)
+        }
+        Some(Target::Stderr) => {
+            io::stderr().write_all(bytes).map(|_| len).map_err(|_| EIO)
+        }
+        Some(Target::File(_)) => {
+            let bytes = bytes.to_vec();
+            emu.vfs.write(fd, &bytes).map(|x| x as u64).map_err(VfsError::errno)
+        }
+        _ => Err(EBADF),
+    };
+
+    set_result(emu, result);
+    Ok(())
+}
+
+/// `fstat(fd, statbuf)`. Files get their type, permissions and size, and
+/// streams an all zero `stat`
+pub fn fstat(emu: &mut Emulator) -> Result<(), VmExit> {
+    let fd      = emu.reg(Register::A0);
+    let statbuf = emu.reg(Register::A1) as usize;
+
+    let result = emu.vfs.size(fd).map_err(VfsError::errno).and_then(|size| {
+        // writing 0 to the `stat` buffer, 128 bytes on riscv64
+        emu.memory.write_from(VirtAddr(statbuf), &[0u8; 0x80])
+            .map_err(|_| EFAULT)?;
+        if let Some(size) = size {
+            // A regular file readable and writable by everyone, `st_mode`
+            // and `st_size`
+            emu.memory.write::<u32>(VirtAddr(statbuf + 16), 0o100666)
+                .and_then(|_| {
+                    emu.memory.write::<u64>(VirtAddr(statbuf + 48), size as u64)
+                })
+                .map_err(|_| EFAULT)?;
+        }
+        Ok(0)
+    });
+    set_result(emu, result);
+    Ok(())
+}
+
+/// `gettimeofday(tv, tz)` with the host's time. A NULL `tv` is skipped
+pub fn gettimeofday(emu: &mut Emulator) -> Result<(), VmExit> {
+    let timeval   = emu.reg(Register::A0) as usize;
+    let _timezone = emu.reg(Register::A1);
+
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+    let result = if timeval == 0 {
+        Ok(0)
+    } else {
+        emu.memory.write::<u64>(VirtAddr(timeval), now.as_secs())
+            .and_then(|_| {
+                emu.memory.write::<u64>(VirtAddr(timeval.wrapping_add(8)),
+                                        now.subsec_micros() as u64)
+            })
+            .map(|_| 0).map_err(|_| EFAULT)
+    };
+    set_result(emu, result);
+    Ok(())
+}
+
+/// `lseek(fd, offset, whence)`
+pub fn lseek(emu: &mut Emulator) -> Result<(), VmExit> {
+    let fd     = emu.reg(Register::A0);
+    let offset = emu.reg(Register::A1) as i64;
+    let whence = emu.reg(Register::A2);
+
+    let result = emu.vfs.lseek(fd, offset, whence).map_err(VfsError::errno);
+    set_result(emu, result);
+    Ok(())
+}
+
+/// `close(fd)`
+pub fn close(emu: &mut Emulator) -> Result<(), VmExit> {
+    let fd = emu.reg(Register::A0);
+
+    let result = emu.vfs.close(fd).map(|_| 0).map_err(VfsError::errno);
+    set_result(emu, result);
+    Ok(())
+}
+
+/// `dup(fd)`, the new descriptor shares the offset of `fd`
+pub fn dup(emu: &mut Emulator) -> Result<(), VmExit> {
+    let fd = emu.reg(Register::A0);
+
+    let result = emu.vfs.dup(fd).map_err(VfsError::errno);
+    set_result(emu, result);
+    Ok(())
+}
+
+/// `exit(status)`, the status stays in `a0`
+pub fn exit(_emu: &mut Emulator) -> Result<(), VmExit> {
+    Err(VmExit::Exit)
+}
@@ -0,0 +1,207 @@
+//! Byte level taint tracking of guest input
+//!
+//! Every byte read by the guest from its input gets a unique label. Labels
+//! flowing together through an instruction are merged into union labels, so
+//! any label can be resolved back to the input bytes which influenced it.
+
+use std::collections::{BTreeSet, HashMap};
+use crate::emulator::Register;
+use crate::mmu::VirtAddr;
+
+/// A taint label, `TaintLabel::CLEAN` marks data not derived from input
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug, Default)]
+#[repr(transparent)]
+pub struct TaintLabel(pub u32);
+
+impl TaintLabel {
+    /// Label of untainted data
+    pub const CLEAN: TaintLabel = TaintLabel(0);
+
+    /// Check if the label carries any taint
+    pub fn is_tainted(self) -> bool {
+        self != TaintLabel::CLEAN
+    }
+}
+
+/// What a label in the table stands for
+#[derive(Clone, Copy, Debug)]
+enum LabelNode {
+    /// Untainted data, only used for `TaintLabel::CLEAN`
+    Clean,
+
+    /// A single input byte at this offset in the input stream
+    Input(usize),
+
+    /// The union of two other labels
+    Union(TaintLabel, TaintLabel),
+}
+
+/// Where tainted data was used to make a decision
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TaintUse {
+    /// An operand of a conditional branch
+    Branch,
+
+    /// The target of a JALR
+    JumpTarget,
+
+    /// The base address of a store
+    StoreAddress,
+}
+
+/// A use of tainted data by the guest
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TaintEvent {
+    /// Address of the instruction which used the data
+    pub pc: VirtAddr,
+
+    /// How the data was used
+    pub kind: TaintUse,
+
+    /// Label of the data, resolve it with `Taint::sources`
+    pub label: TaintLabel,
+}
+
+/// Taint state of the guest registers and the label table
+#[derive(Clone)]
+pub struct Taint {
+    /// All labels handed out, indexed by the label value
+    labels: Vec<LabelNode>,
+
+    /// Cache of union labels so merging the same pair reuses a label
+    unions: HashMap<(TaintLabel, TaintLabel), TaintLabel>,
+
+    /// Labels of the guest registers
+    registers: [TaintLabel; 33],
+
+    /// Offset in the input stream of the next input byte
+    input_pos: usize,
+
+    /// Uses of tainted data in the order they happened
+    events: Vec<TaintEvent>,
+}
+
+impl Default for Taint {
+    fn default() -> Self {
+        Taint::new()
+    }
+}
+
+impl Taint {
+    /// Create an empty taint state with all registers clean
+    pub fn new() -> Self {
+        Taint {
+            labels:    vec![LabelNode::Clean],
+            unions:    HashMap::new(),
+            registers: [TaintLabel::CLEAN; 33],
+            input_pos: 0,
+            events:    Vec::new(),
+        }
+    }
+
+    /// Get a fresh label for the next byte of the input stream
+    pub fn next_input(&mut self) -> TaintLabel {
+        let label = TaintLabel(self.labels.len() as u32);
+        self.labels.push(LabelNode::Input(self.input_pos));
+        self.input_pos += 1;
+        label
+    }
+
+    /// Merge two labels
+    pub fn union(&mut self, a: TaintLabel, b: TaintLabel) -> TaintLabel {
+        if a == b || !b.is_tainted() {
+            return a;
+        }
+        if !a.is_tainted() {
+            return b;
+        }
+
+        let key = (a.min(b), a.max(b));
+        if let Some(&label) = self.unions.get(&key) {
+            return label;
+        }
+
+        let label = TaintLabel(self.labels.len() as u32);
+        self.labels.push(LabelNode::Union(key.0, key.1));
+        self.unions.insert(key, label);
+        label
+    }
+
+    /// Merge all labels in `labels`
+    pub fn union_all(&mut self, labels: &[TaintLabel]) -> TaintLabel {
+        labels.iter().fold(TaintLabel::CLEAN, |acc, &x| self.union(acc, x))
+    }
+
+    /// Get the label of a register
+    pub fn reg(&self, register: Register) -> TaintLabel {
+        if register != Register::Zero {
+            self.registers[register as usize]
+        } else {
+            TaintLabel::CLEAN
+        }
+    }
+
+    /// Set the label of a register
+    pub fn set_reg(&mut self, register: Register, label: TaintLabel) {
+        if register != Register::Zero {
+            self.registers[register as usize] = label;
+        }
+    }
+
+    /// Merge the labels of all registers in `srcs`
+    fn union_regs(&mut self, srcs: &[Register]) -> TaintLabel {
+        srcs.iter().fold(TaintLabel::CLEAN, |acc, &x| {
+            let src = self.reg(x);
+            self.union(acc, src)
+        })
+    }
+
+    /// Give `rd` the union of the labels of `srcs`
+    pub fn flow(&mut self, rd: Register, srcs: &[Register]) {
+        let label = self.union_regs(srcs);
+        self.set_reg(rd, label);
+    }
+
+    /// Record a use of `srcs` at `pc` if any of them is tainted
+    pub fn record(&mut self, pc: VirtAddr, kind: TaintUse, srcs: &[Register]) {
+        let label = self.union_regs(srcs);
+        if label.is_tainted() {
+            self.events.push(TaintEvent { pc, kind, label });
+        }
+    }
+
+    /// Uses of tainted data recorded so far
+    pub fn events(&self) -> &[TaintEvent] {
+        &self.events
+    }
+
+    /// Forget all recorded uses
+    pub fn clear_events(&mut self) {
+        self.events.clear();
+    }
+
+    /// Resolve `label` into the sorted offsets of the input bytes it was
+    /// derived from
+    pub fn sources(&self, label: TaintLabel) -> Vec<usize> {
+        let mut sources = BTreeSet::new();
+        let mut seen    = BTreeSet::new();
+        let mut stack   = vec![label];
+
+        while let Some(label) = stack.pop() {
+            if !seen.insert(label) {
+                continue;
+            }
+
+            match self.labels.get(label.0 as usize) {
+                Some(LabelNode::Input(offset)) => { sources.insert(*offset); }
+                Some(LabelNode::Union(a, b)) => {
+                    stack.push(*a);
+                    stack.push(*b);
+                }
+                Some(LabelNode::Clean) | None => {}
+            }
+        }
+
+        sources.into_iter().collect()
+    }
+}
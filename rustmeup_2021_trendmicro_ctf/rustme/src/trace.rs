@@ -0,0 +1,107 @@
+//! Tracing of guest memory accesses
+
+use std::fmt;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use crate::mmu::{VirtAddr, Perm, PERM_WRITE};
+use crate::symbols::Symbols;
+
+/// A single load or store performed by the guest
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MemAccess {
+    /// Address of the instruction which performed the access
+    pub pc: VirtAddr,
+
+    /// Address accessed
+    pub addr: VirtAddr,
+
+    /// Number of bytes accessed
+    pub size: usize,
+
+    /// Value loaded or stored, zero extended from `size` bytes
+    pub value: u64,
+
+    /// `PERM_READ` for loads, `PERM_WRITE` for stores
+    pub kind: Perm,
+}
+
+impl fmt::Display for MemAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = if (self.kind.0 & PERM_WRITE) != 0 { "write" } else { "read" };
+        write!(f, "{} {:#x} {} bytes {:#x} at pc {:#x}", kind, self.addr.0,
+               self.size, self.value, self.pc.0)
+    }
+}
+
+/// Receiver of traced memory accesses
+pub trait TraceSink {
+    /// Handle one access
+    fn record(&mut self, access: &MemAccess);
+}
+
+/// Collect accesses in memory
+impl TraceSink for Vec<MemAccess> {
+    fn record(&mut self, access: &MemAccess) {
+        self.push(*access);
+    }
+}
+
+/// Writes accesses to `out` a line each, naming the code which made them
+/// when the guest has symbols
+pub struct TraceLog<W> {
+    out: W,
+    symbols: Option<Arc<Symbols>>,
+}
+
+impl<W: Write> TraceLog<W> {
+    /// Log accesses to `out`, locating their PCs with `symbols`
+    pub fn new(out: W, symbols: Option<Arc<Symbols>>) -> Self {
+        TraceLog { out, symbols }
+    }
+}
+
+impl<W: Write> TraceSink for TraceLog<W> {
+    fn record(&mut self, access: &MemAccess) {
+        // The trace is best effort, a closed output doesn't stop the guest
+        let _ = match &self.symbols {
+            Some(symbols) => writeln!(self.out, "{} {}", access,
+                                      symbols.locate(access.pc)),
+            None => writeln!(self.out, "{}", access),
+        };
+    }
+}
+
+/// A shareable handle to a sink, so the caller can inspect it while tracing
+pub type TraceHandle = Arc<Mutex<dyn TraceSink + Send>>;
+
+/// Sends the accesses touching the filtered ranges to a sink
+pub struct Tracer {
+    /// Sink receiving the accesses
+    sink: TraceHandle,
+
+    /// Address ranges as (start, end) pairs. Accesses touching any of them
+    /// are traced, all accesses are traced if there are none
+    filters: Vec<(VirtAddr, usize)>,
+}
+
+impl Tracer {
+    /// Trace all accesses into `sink`
+    pub fn new(sink: TraceHandle) -> Self {
+        Tracer { sink, filters: Vec::new() }
+    }
+
+    /// Only trace accesses touching `size` bytes at `addr` and any other
+    /// range added
+    pub fn add_filter(&mut self, addr: VirtAddr, size: usize) {
+        self.filters.push((addr, addr.0.saturating_add(size)));
+    }
+
+    /// Send an access to the sink if it passes the filters
+    pub fn trace(&self, access: &MemAccess) {
+        let end = access.addr.0 + access.size;
+        if self.filters.is_empty() || self.filters.iter()
+                .any(|&(start, stop)| access.addr.0 < stop && start.0 < end) {
+            self.sink.lock().unwrap().record(access);
+        }
+    }
+}
@@ -0,0 +1,690 @@
+//! In-memory filesystem and file descriptor table of the guest
+//!
+//! Files are either in-memory contents or host files, read into memory the
+//! first time the guest opens them. Guest writes only ever change the
+//! in-memory copy. Descriptors point at open file descriptions holding the
+//! offset, so descriptors made by `dup` share it like on Linux. The guest's
+//! working directory is always the root.
+//!
+//! A `HostRoot` makes the files of a host directory visible under the root
+//! as well. Files added to the `Vfs` hide host files at the same path.
+//!
+//! Files and descriptors are saved in snapshots. The `HostRoot` is host side
+//! state and is not, it is set again on the loaded `Vfs`.
+
+use crate::hostfs::{HostError, HostMode, HostRoot};
+use crate::snapshot::{self, read_bytes, read_string, read_u64, read_usize,
+                      write_bytes, write_u64};
+use crate::syscall::{EACCES, EBADF, EEXIST, EFBIG, EINVAL, EIO, EMFILE, ENOENT,
+                     EROFS, ESPIPE, PATH_MAX};
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Open flags of the generic Linux ABI
+pub const O_ACCMODE: u64 = 0o3;
+pub const O_RDONLY:  u64 = 0o0;
+pub const O_WRONLY:  u64 = 0o1;
+pub const O_RDWR:    u64 = 0o2;
+pub const O_CREAT:   u64 = 0o100;
+pub const O_EXCL:    u64 = 0o200;
+pub const O_TRUNC:   u64 = 0o1000;
+pub const O_APPEND:  u64 = 0o2000;
+
+/// `lseek` whence values
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+/// Highest number of descriptors open at once
+const MAX_FDS: usize = 1024;
+
+/// Largest a file may grow to, files live in host memory
+pub const MAX_FILE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Why a filesystem operation failed
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VfsError {
+    /// The descriptor is not open, or not open for the access
+    BadFd,
+
+    /// No file at the path
+    NotFound,
+
+    /// `O_CREAT | O_EXCL` of a file which exists
+    Exists,
+
+    /// A bad argument, such as a negative offset
+    Invalid,
+
+    /// Seek on a stream
+    NotSeekable,
+
+    /// Every descriptor is in use
+    TooManyFiles,
+
+    /// A write would grow a file past `MAX_FILE_SIZE`
+    TooBig,
+
+    /// A host file backing the path could not be read
+    Io,
+
+    /// The host root rules deny the path
+    Denied,
+
+    /// Write access to a read-only host root
+    ReadOnly,
+}
+
+impl fmt::Display for VfsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            VfsError::BadFd        => "bad file descriptor",
+            VfsError::NotFound     => "no such file",
+            VfsError::Exists       => "file exists",
+            VfsError::Invalid      => "invalid argument",
+            VfsError::NotSeekable  => "illegal seek",
+            VfsError::TooManyFiles => "too many open files",
+            VfsError::TooBig       => "file too large",
+            VfsError::Io           => "host I/O error",
+            VfsError::Denied       => "permission denied",
+            VfsError::ReadOnly     => "read-only file system",
+        })
+    }
+}
+
+impl VfsError {
+    /// The Linux error number the guest sees for this error
+    pub fn errno(self) -> u64 {
+        match self {
+            VfsError::BadFd        => EBADF,
+            VfsError::NotFound     => ENOENT,
+            VfsError::Exists       => EEXIST,
+            VfsError::Invalid      => EINVAL,
+            VfsError::NotSeekable  => ESPIPE,
+            VfsError::TooManyFiles => EMFILE,
+            VfsError::TooBig       => EFBIG,
+            VfsError::Io           => EIO,
+            VfsError::Denied       => EACCES,
+            VfsError::ReadOnly     => EROFS,
+        }
+    }
+}
+
+/// Contents of a file
+#[derive(Clone, Debug)]
+enum Node {
+    /// Bytes held in memory, shared until written
+    Memory(Arc<Vec<u8>>),
+
+    /// A host file, read into memory when first opened
+    Host(PathBuf),
+}
+
+/// What an open file description refers to
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Target {
+    /// The emulator's stdin `Input`
+    Stdin,
+
+    /// The host's stdout
+    Stdout,
+
+    /// The host's stderr
+    Stderr,
+
+    /// The file at a normalized path
+    File(String),
+}
+
+/// An open file, shared by every descriptor `dup`ed from the same `open`
+#[derive(Clone, Debug)]
+struct Description {
+    target: Target,
+    offset: usize,
+    flags:  u64,
+
+    /// Number of descriptors pointing here
+    refs: usize,
+}
+
+/// The guest's files and descriptors
+#[derive(Clone, Debug)]
+pub struct Vfs {
+    /// Files by normalized path
+    files: BTreeMap<String, Node>,
+
+    /// Open file descriptions, `None` once unreferenced
+    descriptions: Vec<Option<Description>>,
+
+    /// Description of every descriptor, `None` if closed
+    fds: Vec<Option<usize>>,
+
+    /// Host directory mapped under the root, shared with forks
+    root: Option<Arc<HostRoot>>,
+
+    /// Paths of `files` which came from `root`
+    root_files: BTreeSet<String>,
+}
+
+impl Default for Vfs {
+    fn default() -> Self {
+        Vfs::new()
+    }
+}
+
+/// Normalize `path` relative to the root, resolving `.` and `..`. Returns
+/// `None` for paths which are not UTF-8
+pub fn normalize(path: &[u8]) -> Option<String> {
+    let path = std::str::from_utf8(path).ok()?;
+
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".."     => { parts.pop(); }
+            part     => parts.push(part),
+        }
+    }
+    Some(parts.join("/"))
+}
+
+impl Vfs {
+    /// Create a filesystem without files, with the standard streams open as
+    /// descriptors 0, 1 and 2
+    pub fn new() -> Self {
+        let mut vfs = Vfs {
+            files:        BTreeMap::new(),
+            descriptions: Vec::new(),
+            fds:          Vec::new(),
+            root:         None,
+            root_files:   BTreeSet::new(),
+        };
+        for (target, flags) in [(Target::Stdin, O_RDONLY),
+                                (Target::Stdout, O_WRONLY),
+                                (Target::Stderr, O_WRONLY)] {
+            vfs.install(target, flags).unwrap();
+        }
+        vfs
+    }
+
+    /// Add a file at `path` holding `contents`, replacing any file there
+    pub fn add_file(&mut self, path: &str, contents: Vec<u8>) {
+        let path = normalize(path.as_bytes()).unwrap();
+        self.files.insert(path, Node::Memory(Arc::new(contents)));
+    }
+
+    /// Add a file at `path` backed by the host file `host_path`. The host
+    /// file is read when the guest first opens it
+    pub fn add_host_file(&mut self, path: &str, host_path: impl Into<PathBuf>) {
+        let path = normalize(path.as_bytes()).unwrap();
+        self.files.insert(path, Node::Host(host_path.into()));
+    }
+
+    /// Make the files of `root` visible to the guest
+    pub fn set_root(&mut self, root: HostRoot) {
+        self.root = Some(Arc::new(root));
+    }
+
+    /// Get the in-memory contents of the file at `path`, `None` if there is
+    /// no such file or it has not been read from the host yet
+    pub fn file(&self, path: &str) -> Option<&[u8]> {
+        match self.files.get(&normalize(path.as_bytes())?)? {
+            Node::Memory(contents) => Some(contents),
+            Node::Host(_)          => None,
+        }
+    }
+
+    /// Get what descriptor `fd` refers to
+    pub fn target(&self, fd: u64) -> Option<&Target> {
+        self.description(fd).ok().map(|x| &x.target)
+    }
+
+    /// Check that `fd` is open for writing
+    pub fn writable(&self, fd: u64) -> Result<(), VfsError> {
+        match self.description(fd)?.flags & O_ACCMODE {
+            O_RDONLY => Err(VfsError::BadFd),
+            _ => Ok(()),
+        }
+    }
+
+    /// Get the index of the description of `fd`
+    fn description_idx(&self, fd: u64) -> Result<usize, VfsError> {
+        usize::try_from(fd).ok().and_then(|fd| self.fds.get(fd))
+            .copied().flatten().ok_or(VfsError::BadFd)
+    }
+
+    /// Get the description of `fd`
+    fn description(&self, fd: u64) -> Result<&Description, VfsError> {
+        let idx = self.description_idx(fd)?;
+        Ok(self.descriptions[idx].as_ref().unwrap())
+    }
+
+    /// Get the description of `fd` to change it
+    fn description_mut(&mut self, fd: u64) -> Result<&mut Description, VfsError> {
+        let idx = self.description_idx(fd)?;
+        Ok(self.descriptions[idx].as_mut().unwrap())
+    }
+
+    /// Point the lowest free descriptor at description `idx`
+    fn allocate_fd(&mut self, idx: usize) -> Result<u64, VfsError> {
+        let fd = match self.fds.iter().position(|x| x.is_none()) {
+            Some(fd) => fd,
+            None if self.fds.len() < MAX_FDS => {
+                self.fds.push(None);
+                self.fds.len() - 1
+            }
+            None => return Err(VfsError::TooManyFiles),
+        };
+
+        self.fds[fd] = Some(idx);
+        self.descriptions[idx].as_mut().unwrap().refs += 1;
+        Ok(fd as u64)
+    }
+
+    /// Open a new description of `target` on the lowest free descriptor
+    fn install(&mut self, target: Target, flags: u64) -> Result<u64, VfsError> {
+        let description = Description { target, offset: 0, flags, refs: 0 };
+        let idx = match self.descriptions.iter().position(|x| x.is_none()) {
+            Some(idx) => {
+                self.descriptions[idx] = Some(description);
+                idx
+            }
+            None => {
+                self.descriptions.push(Some(description));
+                self.descriptions.len() - 1
+            }
+        };
+
+        self.allocate_fd(idx).inspect_err(|_| {
+            self.descriptions[idx] = None;
+        })
+    }
+
+    /// Get the contents of the file at `path` to change them, reading host
+    /// files in
+    fn contents_mut(&mut self, path: &str) -> Result<&mut Vec<u8>, VfsError> {
+        let node = self.files.get_mut(path).ok_or(VfsError::NotFound)?;
+        if let Node::Host(host_path) = node {
+            let contents = std::fs::read(host_path).map_err(|_| VfsError::Io)?;
+            *node = Node::Memory(Arc::new(contents));
+        }
+
+        match node {
+            Node::Memory(contents) => Ok(Arc::make_mut(contents)),
+            Node::Host(_)          => unreachable!(),
+        }
+    }
+
+    /// Get the contents of the open file at `path`. Files replaced since
+    /// they were opened are empty until written
+    fn contents(&self, path: &str) -> &[u8] {
+        match self.files.get(path) {
+            Some(Node::Memory(contents)) => contents,
+            _ => &[],
+        }
+    }
+
+    /// Open the file at `path` with the `O_*` `flags`, returning the new
+    /// descriptor
+    pub fn open(&mut self, path: &[u8], flags: u64) -> Result<u64, VfsError> {
+        let path = normalize(path).ok_or(VfsError::Invalid)?;
+        if path.is_empty() {
+            return Err(VfsError::NotFound);
+        }
+
+        // Look the path up in the host root the first time it is used
+        if let Some(root) = self.root.as_ref().filter(|_| !self.files.contains_key(&path)) {
+            match root.resolve(&path) {
+                Ok(host) => {
+                    self.files.insert(path.clone(), Node::Host(host));
+                    self.root_files.insert(path.clone());
+                }
+                Err(HostError::Denied) => return Err(VfsError::Denied),
+                Err(HostError::NotFound) => {
+                    // Files created under the root follow its rules
+                    if flags & O_CREAT != 0 {
+                        if root.mode() == HostMode::ReadOnly {
+                            return Err(VfsError::ReadOnly);
+                        }
+                        if !root.permits(&path) {
+                            return Err(VfsError::Denied);
+                        }
+                    }
+                }
+            }
+        }
+
+        // Writes to read-only host files are refused up front
+        let writes = flags & O_ACCMODE != O_RDONLY || flags & O_TRUNC != 0;
+        if writes && self.root_files.contains(&path) &&
+                self.root.as_ref().map(|x| x.mode()) == Some(HostMode::ReadOnly) {
+            return Err(VfsError::ReadOnly);
+        }
+
+        if !self.files.contains_key(&path) {
+            if flags & O_CREAT == 0 {
+                return Err(VfsError::NotFound);
+            }
+            self.files.insert(path.clone(), Node::Memory(Arc::new(Vec::new())));
+        } else if flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL {
+            return Err(VfsError::Exists);
+        }
+
+        // Bring host files into memory now, so a missing one fails the open
+        let contents = self.contents_mut(&path)?;
+        if flags & O_TRUNC != 0 && flags & O_ACCMODE != O_RDONLY {
+            contents.clear();
+        }
+
+        self.install(Target::File(path), flags)
+    }
+
+    /// Read up to `count` bytes from `fd` at its offset, advancing it. Only
+    /// files are handled, streams are up to the caller
+    pub fn read(&mut self, fd: u64, count: usize) -> Result<Vec<u8>, VfsError> {
+        let description = self.description(fd)?;
+        if description.flags & O_ACCMODE == O_WRONLY {
+            return Err(VfsError::BadFd);
+        }
+        let (path, offset) = match &description.target {
+            Target::File(path) => (path.clone(), description.offset),
+            _ => return Err(VfsError::Invalid),
+        };
+
+        let contents = self.contents(&path);
+        let start = offset.min(contents.len());
+        let end = start.saturating_add(count).min(contents.len());
+        let data = contents[start..end].to_vec();
+
+        self.description_mut(fd)?.offset = end;
+        Ok(data)
+    }
+
+    /// Write `data` to `fd` at its offset, or at the end in append mode,
+    /// advancing it. Only files are handled, streams are up to the caller
+    pub fn write(&mut self, fd: u64, data: &[u8]) -> Result<usize, VfsError> {
+        let description = self.description(fd)?;
+        if description.flags & O_ACCMODE == O_RDONLY {
+            return Err(VfsError::BadFd);
+        }
+        let (path, offset, append) = match &description.target {
+            Target::File(path) => (path.clone(), description.offset,
+                                   description.flags & O_APPEND != 0),
+            _ => return Err(VfsError::Invalid),
+        };
+
+        let contents = self.contents_mut(&path)?;
+        let start = if append { contents.len() } else { offset };
+        let end = start.checked_add(data.len()).filter(|&x| x <= MAX_FILE_SIZE)
+            .ok_or(VfsError::TooBig)?;
+        if contents.len() < end {
+            contents.resize(end, 0);
+        }
+        contents[start..end].copy_from_slice(data);
+
+        self.description_mut(fd)?.offset = end;
+        Ok(data.len())
+    }
+
+    /// Move the offset of `fd` as `lseek` does, returning the new offset.
+    /// Offsets past `MAX_FILE_SIZE` are invalid
+    pub fn lseek(&mut self, fd: u64, offset: i64, whence: u64)
+            -> Result<u64, VfsError> {
+        let description = self.description(fd)?;
+        let path = match &description.target {
+            Target::File(path) => path.clone(),
+            _ => return Err(VfsError::NotSeekable),
+        };
+
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => description.offset as i64,
+            SEEK_END => self.contents(&path).len() as i64,
+            _ => return Err(VfsError::Invalid),
+        };
+        let new = base.checked_add(offset)
+            .filter(|&x| x >= 0 && x as u64 <= MAX_FILE_SIZE as u64)
+            .ok_or(VfsError::Invalid)?;
+
+        self.description_mut(fd)?.offset = new as usize;
+        Ok(new as u64)
+    }
+
+    /// Size of the file `fd` refers to, `None` for streams
+    pub fn size(&self, fd: u64) -> Result<Option<usize>, VfsError> {
+        Ok(match &self.description(fd)?.target {
+            Target::File(path) => Some(self.contents(path).len()),
+            _ => None,
+        })
+    }
+
+    /// Make a new descriptor sharing the description of `fd`
+    pub fn dup(&mut self, fd: u64) -> Result<u64, VfsError> {
+        let idx = self.description_idx(fd)?;
+        self.allocate_fd(idx)
+    }
+
+    /// Close `fd`, dropping its description once no descriptor uses it
+    pub fn close(&mut self, fd: u64) -> Result<(), VfsError> {
+        let idx = self.description_idx(fd)?;
+        self.fds[fd as usize] = None;
+
+        let description = self.descriptions[idx].as_mut().unwrap();
+        description.refs -= 1;
+        if description.refs == 0 {
+            self.descriptions[idx] = None;
+        }
+        Ok(())
+    }
+
+    /// Serialize the files, open file descriptions and descriptors into a
+    /// snapshot. Host files not read in yet are saved as their host path
+    pub fn save_snapshot<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_u64(w, self.files.len() as u64)?;
+        for (path, node) in &self.files {
+            write_bytes(w, path.as_bytes())?;
+            match node {
+                Node::Memory(contents) => {
+                    write_u64(w, 0)?;
+                    write_bytes(w, contents)?;
+                }
+                Node::Host(host_path) => {
+                    let host_path = host_path.to_str().ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidInput,
+                                       "host path is not UTF-8")
+                    })?;
+                    write_u64(w, 1)?;
+                    write_bytes(w, host_path.as_bytes())?;
+                }
+            }
+        }
+
+        write_u64(w, self.descriptions.len() as u64)?;
+        for description in &self.descriptions {
+            let description = match description {
+                Some(description) => description,
+                None => {
+                    write_u64(w, !0)?;
+                    continue;
+                }
+            };
+
+            match &description.target {
+                Target::Stdin  => write_u64(w, 0)?,
+                Target::Stdout => write_u64(w, 1)?,
+                Target::Stderr => write_u64(w, 2)?,
+                Target::File(path) => {
+                    write_u64(w, 3)?;
+                    write_bytes(w, path.as_bytes())?;
+                }
+            }
+            write_u64(w, description.offset as u64)?;
+            write_u64(w, description.flags)?;
+        }
+
+        write_u64(w, self.fds.len() as u64)?;
+        for fd in &self.fds {
+            write_u64(w, fd.map(|x| x as u64).unwrap_or(!0))?;
+        }
+
+        write_u64(w, self.root_files.len() as u64)?;
+        for path in &self.root_files {
+            write_bytes(w, path.as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Create a filesystem from a snapshot written by `save_snapshot`. The
+    /// result has no `HostRoot`
+    pub fn load_snapshot<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut vfs = Vfs {
+            files:        BTreeMap::new(),
+            descriptions: Vec::new(),
+            fds:          Vec::new(),
+            root:         None,
+            root_files:   BTreeSet::new(),
+        };
+
+        let files = read_usize(r)?;
+        for _ in 0..files {
+            let path = read_string(r, PATH_MAX)?;
+            let node = match read_u64(r)? {
+                0 => Node::Memory(Arc::new(read_bytes(r, MAX_FILE_SIZE)?)),
+                1 => Node::Host(read_string(r, PATH_MAX)?.into()),
+                _ => return Err(snapshot::invalid("unknown file kind")),
+            };
+            vfs.files.insert(path, node);
+        }
+
+        // Every description is referenced by at least one descriptor
+        let descriptions = read_usize(r)?;
+        if descriptions > MAX_FDS {
+            return Err(snapshot::invalid("too many file descriptions"));
+        }
+        for _ in 0..descriptions {
+            let target = match read_u64(r)? {
+                0 => Target::Stdin,
+                1 => Target::Stdout,
+                2 => Target::Stderr,
+                3 => Target::File(read_string(r, PATH_MAX)?),
+                u64::MAX => {
+                    vfs.descriptions.push(None);
+                    continue;
+                }
+                _ => return Err(snapshot::invalid("unknown file target")),
+            };
+            let offset = read_usize(r)?;
+            let flags = read_u64(r)?;
+            vfs.descriptions.push(Some(Description {
+                target, offset, flags, refs: 0,
+            }));
+        }
+
+        let fds = read_usize(r)?;
+        if fds > MAX_FDS {
+            return Err(snapshot::invalid("too many file descriptors"));
+        }
+        for _ in 0..fds {
+            let fd = match read_u64(r)? {
+                u64::MAX => None,
+                idx => {
+                    let description = usize::try_from(idx).ok()
+                        .and_then(|x| vfs.descriptions.get_mut(x))
+                        .and_then(|x| x.as_mut())
+                        .ok_or_else(|| snapshot::invalid("bad file descriptor"))?;
+                    description.refs += 1;
+                    Some(idx as usize)
+                }
+            };
+            vfs.fds.push(fd);
+        }
+        if vfs.descriptions.iter().flatten().any(|x| x.refs == 0) {
+            return Err(snapshot::invalid("unreferenced file description"));
+        }
+
+        let root_files = read_usize(r)?;
+        for _ in 0..root_files {
+            vfs.root_files.insert(read_string(r, PATH_MAX)?);
+        }
+
+        Ok(vfs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_stop_growing_at_the_size_limit() {
+        let mut vfs = Vfs::new();
+        let fd = vfs.open(b"/big", O_CREAT | O_RDWR).unwrap();
+
+        assert_eq!(vfs.lseek(fd, 0x7fff_ffff_ffff_0000, SEEK_SET),
+                   Err(VfsError::Invalid));
+        assert_eq!(vfs.lseek(fd, MAX_FILE_SIZE as i64 + 1, SEEK_SET),
+                   Err(VfsError::Invalid));
+
+        assert_eq!(vfs.lseek(fd, MAX_FILE_SIZE as i64, SEEK_SET),
+                   Ok(MAX_FILE_SIZE as u64));
+        assert_eq!(vfs.write(fd, b"x"), Err(VfsError::TooBig));
+        assert_eq!(vfs.size(fd), Ok(Some(0)));
+
+        assert_eq!(vfs.lseek(fd, MAX_FILE_SIZE as i64 - 1, SEEK_SET),
+                   Ok(MAX_FILE_SIZE as u64 - 1));
+        assert_eq!(vfs.write(fd, b"x"), Ok(1));
+        assert_eq!(vfs.size(fd), Ok(Some(MAX_FILE_SIZE)));
+    }
+
+    #[test]
+    fn snapshots_keep_files_and_descriptors() {
+        let mut vfs = Vfs::new();
+        vfs.add_file("/flag", b"0123456789".to_vec());
+        vfs.add_host_file("/host", "/nonexistent/host");
+
+        let flag = vfs.open(b"/flag", O_RDONLY).unwrap();
+        let out = vfs.open(b"/out", O_CREAT | O_WRONLY).unwrap();
+        assert_eq!(vfs.write(out, b"abc"), Ok(3));
+        assert_eq!(vfs.read(flag, 4), Ok(b"0123".to_vec()));
+        let copy = vfs.dup(flag).unwrap();
+        vfs.close(out).unwrap();
+
+        let mut file = Vec::new();
+        vfs.save_snapshot(&mut file).unwrap();
+        let mut loaded = Vfs::load_snapshot(&mut &file[..]).unwrap();
+
+        // Offsets are kept and still shared by `dup`ed descriptors
+        assert_eq!(loaded.target(0), Some(&Target::Stdin));
+        assert_eq!(loaded.target(2), Some(&Target::Stderr));
+        assert_eq!(loaded.read(flag, 2), Ok(b"45".to_vec()));
+        assert_eq!(loaded.read(copy, 2), Ok(b"67".to_vec()));
+        assert_eq!(loaded.target(out), None);
+        assert_eq!(loaded.file("/out"), Some(&b"abc"[..]));
+        assert_eq!(loaded.file("/host"), None);
+
+        // Closing one descriptor keeps the shared description open
+        loaded.close(flag).unwrap();
+        assert_eq!(loaded.read(copy, 8), Ok(b"89".to_vec()));
+        assert_eq!(loaded.open(b"/x", O_RDONLY), Err(VfsError::NotFound));
+    }
+
+    #[test]
+    fn snapshots_with_dangling_descriptors_are_rejected() {
+        let mut vfs = Vfs::new();
+        vfs.close(1).unwrap();
+
+        let mut file = Vec::new();
+        vfs.save_snapshot(&mut file).unwrap();
+
+        // Point descriptor 0 at the freed description
+        let fds = file.len() - 8 * 4;
+        file[fds..fds + 8].copy_from_slice(&1u64.to_le_bytes());
+        let err = Vfs::load_snapshot(&mut &file[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}